@@ -0,0 +1,134 @@
+#![no_std]
+
+// Reference flash loan receiver for the Stellar DeFi Hub.
+// Swaps a user's collateral from one asset to another in a single transaction:
+// flash borrow the new asset, supply it for the user, withdraw the old collateral,
+// swap it back into the new asset and approve the hub to pull its repayment.
+
+use soroban_sdk::{
+    contract, contractimpl, contracttype, symbol_short, xdr::FromXdr, Address, Bytes, Env,
+    IntoVal, Symbol,
+};
+
+// Parameters passed through `flash_loan` as XDR
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CollateralSwapParams {
+    pub user: Address,
+    pub from_asset: Address, // Collateral being replaced
+    pub withdraw_amount: u128, // Amount of from_asset to withdraw and swap
+    pub min_out: u128, // Minimum new asset out of the swap, must cover loan + fee
+}
+
+#[contract]
+pub struct CollateralSwapReceiver;
+
+#[contractimpl]
+impl CollateralSwapReceiver {
+
+    /// Initialize the receiver with the hub it accepts loans from
+    pub fn initialize(env: Env, hub: Address) {
+        assert!(!env.storage().instance().has(&symbol_short!("hub")), "Already initialized");
+        env.storage().instance().set(&symbol_short!("hub"), &hub);
+    }
+
+    /// Flash loan callback, invoked by the hub after sending `amount` of `asset`
+    pub fn exec_op(env: Env, asset: Address, amount: u128, fee: u128, params: Bytes) -> bool {
+        let hub: Address = env.storage().instance().get(&symbol_short!("hub")).unwrap();
+        // Only the hub may drive this callback
+        hub.require_auth();
+
+        let params = CollateralSwapParams::from_xdr(&env, &params)
+            .unwrap_or_else(|_| panic!("Invalid collateral swap params"));
+        // The user authorizes every hub call below as part of the same transaction
+        let user = params.user;
+
+        // Hand the borrowed asset to the user and supply it as their new collateral
+        Self::transfer(&env, &asset, &user, amount);
+        env.invoke_contract::<Address>(
+            &hub,
+            &Symbol::new(&env, "supply_to_blend"),
+            soroban_sdk::vec![
+                &env,
+                user.into_val(&env),
+                asset.into_val(&env),
+                amount.into_val(&env),
+            ],
+        );
+
+        // Release the old collateral (the hub takes back its bTokens) and swap it into the new asset
+        env.invoke_contract::<u128>(
+            &hub,
+            &Symbol::new(&env, "withdraw_from_blend"),
+            soroban_sdk::vec![
+                &env,
+                user.into_val(&env),
+                params.from_asset.into_val(&env),
+                params.withdraw_amount.into_val(&env),
+            ],
+        );
+        let received = env.invoke_contract::<u128>(
+            &hub,
+            &Symbol::new(&env, "swap_tokens"),
+            soroban_sdk::vec![
+                &env,
+                user.into_val(&env),
+                params.from_asset.into_val(&env),
+                asset.into_val(&env),
+                params.withdraw_amount.into_val(&env),
+                params.min_out.into_val(&env),
+                env.ledger().timestamp().into_val(&env),
+                Option::<Address>::None.into_val(&env),
+            ],
+        );
+        assert!(received >= amount + fee, "Swap does not cover flash loan");
+
+        // Collect the repayment from the swap proceeds; any surplus stays with the user
+        env.invoke_contract::<()>(
+            &asset,
+            &symbol_short!("xferfrom"),
+            soroban_sdk::vec![
+                &env,
+                user.into_val(&env),
+                env.current_contract_address().into_val(&env),
+                (amount + fee).into_val(&env),
+            ],
+        );
+        // The hub pulls loan plus fee once this callback returns
+        env.invoke_contract::<()>(
+            &asset,
+            &symbol_short!("approve"),
+            soroban_sdk::vec![
+                &env,
+                env.current_contract_address().into_val(&env),
+                hub.into_val(&env),
+                (amount + fee).into_val(&env),
+                env.ledger().sequence().into_val(&env),
+            ],
+        );
+
+        env.events().publish(
+            (symbol_short!("coll_swap"), &user),
+            (params.from_asset, asset, params.withdraw_amount, amount, fee)
+        );
+
+        true
+    }
+
+    /// Get the hub this receiver is bound to
+    pub fn get_hub(env: Env) -> Address {
+        env.storage().instance().get(&symbol_short!("hub")).unwrap()
+    }
+
+    fn transfer(env: &Env, token: &Address, to: &Address, amount: u128) {
+        env.invoke_contract::<()>(
+            token,
+            &symbol_short!("transfer"),
+            soroban_sdk::vec![
+                env,
+                to.into_val(env),
+                amount.into_val(env),
+            ],
+        );
+    }
+}
@@ -1,8 +1,8 @@
 #![no_std]
 
 use soroban_sdk::{
-    contract, contractimpl, contracttype, symbol_short, IntoVal,
-    Address, Env, String, Vec, Map
+    contract, contracterror, contractimpl, contracttype, panic_with_error, symbol_short, IntoVal,
+    Address, Bytes, Env, String, Symbol, Vec, Map, U256
 };
 
 // Stellar Blend Pool Interface
@@ -21,6 +21,11 @@ pub struct UserPosition {
     pub staked_lp_tokens: Map<Address, u128>, // LP token -> amount staked
     pub rewards_earned: u128,
     pub last_reward_update: u64, // For reward calculation
+    pub emode_category: u32, // Efficiency mode category, 0 = none
+    pub collateral_enabled: Map<Address, bool>, // asset -> used as collateral, absent = enabled
+    pub supplied_btokens: Map<Address, u128>, // asset -> bTokens issued for the supply
+    pub isolation_asset: Option<Address>, // Isolated collateral the debt was opened against
+    pub isolated_debt: u128, // Debt counted against isolation_asset's ceiling
 }
 
 // Liquidity Pool for staking rewards
@@ -35,17 +40,203 @@ pub struct StakingPool {
 
 // Asset configuration
 #[contracttype]
+#[derive(Clone)]
 pub struct AssetConfig {
     pub address: Address,
     pub symbol: String,
     pub decimals: u32,
-    pub collateral_factor: u128, // In basis points (8000 = 80%)
+    pub borrow_ltv: u128, // Max borrow against this collateral, in basis points (8000 = 80%)
+    pub liquidation_threshold: u128, // Collateral weight for liquidation checks, in basis points
+    pub liquidation_bonus: u128, // Discount paid to liquidators, in basis points
     pub is_collateral: bool,
     pub dia_symbol: String, // Symbol used in DIA oracle
+    pub is_isolated: bool, // Collateral only on its own, against stablecoin debt
+    pub debt_ceiling: u128, // Hub-wide debt cap against this isolated asset (stablecoin units, 0 = none)
+    pub is_stablecoin: bool, // Borrowable in isolation mode
+    pub emode_category: u32, // Efficiency mode category, 0 = none
+    pub supply_cap: u128, // Hub-wide supply limit, 0 = unlimited
+    pub borrow_cap: u128, // Hub-wide borrow limit, 0 = unlimited
 }
 
-// Error types
+// Hub-wide supply and borrow totals for an asset
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AssetTotals {
+    pub total_supplied: u128,
+    pub total_borrowed: u128,
+}
+
+// Position valuation for borrow and liquidation checks
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct BorrowCapacity {
+    pub collateral_value: u128, // Unweighted collateral value
+    pub borrow_limit: u128, // Collateral weighted by borrow LTV
+    pub liquidation_limit: u128, // Collateral weighted by liquidation threshold
+    pub debt_value: u128,
+    pub available: u128, // Remaining borrow_limit after debt
+}
+
+// Efficiency mode category for correlated assets
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct EModeCategory {
+    pub id: u32,
+    pub label: String,
+    pub borrow_ltv: u128, // In basis points, applies when collateral and debt share the category
+    pub liquidation_threshold: u128, // In basis points
+}
+
+// Volume-based fee discount step
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct VolumeDiscount {
+    pub min_volume: u128, // Rolling 30-day swap volume required (6-decimal value units)
+    pub discount: u128, // In basis points off the fee (2500 = 25% off)
+}
+
+// Protocol fee configuration
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct FeeConfig {
+    pub default_fee: u128, // In basis points, used when a pair has no fee tier
+    pub volume_discounts: Vec<VolumeDiscount>, // Sorted by min_volume ascending
+    pub stake_thresholds: Map<Address, u128>, // bToken -> minimum staked to get the staking discount
+    pub stake_discount: u128, // In basis points off the fee
+}
+
+// Referral fee accounting per referrer
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ReferralAccount {
+    pub accrued: Map<Address, u128>, // token -> unclaimed referral fees
+    pub total_earned: Map<Address, u128>, // token -> lifetime referral fees
+    pub referred_swaps: u32,
+}
+
+// Swap leg shared by market swaps and the order types that settle through Soroswap
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SwapParams {
+    pub token_a: Address,
+    pub token_b: Address,
+    pub amount_in: u128,
+    pub min_amount_out: u128,
+}
+
+// Limit order lifecycle
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum OrderStatus {
+    Open,
+    Filled,
+    Cancelled,
+}
+
+// Limit order escrowed in the hub
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct LimitOrder {
+    pub id: u64,
+    pub owner: Address,
+    pub token_a: Address,
+    pub token_b: Address,
+    pub amount_in: u128, // token_a held in escrow
+    pub target_price: u128, // Minimum token_b units per whole token_a
+    pub expiry: u64,
+    pub status: OrderStatus,
+    pub created_at: u64,
+}
+
+// DCA schedule lifecycle
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum DcaStatus {
+    Active,
+    Paused,
+    Cancelled,
+    Completed,
+}
+
+// Recurring swap schedule escrowed in the hub
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct DcaSchedule {
+    pub id: u64,
+    pub owner: Address,
+    pub token_a: Address,
+    pub token_b: Address,
+    pub total_amount: u128,
+    pub remaining: u128, // token_a still held in escrow
+    pub per_interval: u128,
+    pub interval_secs: u64,
+    pub next_execution: u64,
+    pub executions: u32,
+    pub total_received: u128, // token_b delivered so far
+    pub status: DcaStatus,
+}
+
+// Price condition for conditional orders
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum TriggerType {
+    StopLoss, // Executes when the oracle price falls to or below the trigger
+    TakeProfit, // Executes when the oracle price rises to or above the trigger
+}
+
+// Where a conditional order's funds come from
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum OrderBacking {
+    Wallet, // Escrowed in the hub at creation
+    Collateral, // Withdrawn from the user's Blend supply at execution
+}
+
+// Arguments to create_conditional_order
 #[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConditionalOrderParams {
+    pub asset: Address, // Asset sold when triggered
+    pub target_asset: Address, // Asset received
+    pub amount: u128,
+    pub trigger_price: u128, // Oracle price in asset decimals
+    pub trigger: TriggerType,
+    pub backing: OrderBacking,
+    pub max_slippage: u128, // In basis points below the oracle quote
+}
+
+// Stop-loss / take-profit order tied to oracle prices
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ConditionalOrder {
+    pub id: u64,
+    pub owner: Address,
+    pub asset: Address, // Asset sold when triggered
+    pub target_asset: Address, // Asset received
+    pub amount: u128,
+    pub trigger_price: u128, // Oracle price in asset decimals
+    pub trigger: TriggerType,
+    pub backing: OrderBacking,
+    pub max_slippage: u128, // In basis points below the oracle quote
+    pub status: OrderStatus,
+    pub created_at: u64,
+}
+
+// Credit line letting a delegatee borrow against the delegator's collateral
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CreditDelegation {
+    pub delegator: Address, // Collateral owner, carries the debt
+    pub delegatee: Address, // Receives borrowed funds
+    pub asset: Address,
+    pub allowance: u128, // Remaining amount the delegatee may borrow
+    pub borrowed: u128, // Total borrowed through this delegation
+}
+
+// Error types
+#[contracterror]
+#[derive(Copy, Clone, Debug, Eq, PartialEq, PartialOrd, Ord)]
+#[repr(u32)]
 pub enum HubError {
     OracleFailure = 1,
     InsufficientLiquidity = 2,
@@ -55,14 +246,52 @@ pub enum HubError {
     InsufficientCollateral = 6,
     AssetNotSupported = 7,
     SwapFailed = 8,
+    BorrowPaused = 9,
+    IsolationViolation = 10,
+    DebtCeilingExceeded = 11,
+    EModeViolation = 12,
+    SupplyCapExceeded = 13,
+    BorrowCapExceeded = 14,
+    FlashLoanNotRepaid = 15,
+    WithdrawPaused = 16,
 }
 
 #[contract]
 pub struct StellarDeFiHub;
 
-const PROTOCOL_FEE: u128 = 50; // 0.5% (50 basis points)
+const PROTOCOL_FEE: u128 = 50; // Default fee: 0.5% (50 basis points)
+const MAX_PROTOCOL_FEE: u128 = 1000; // 10% cap on any fee tier
+const VOLUME_WINDOW_DAYS: u64 = 30; // Rolling window for fee discounts
+const VOLUME_PRECISION: u128 = 1_000_000; // Swap volume is valued with 6 decimals
+const DEFAULT_REFERRAL_SHARE: u128 = 2000; // 20% of swap fees go to the referrer
+const MAX_REFERRAL_SHARE: u128 = 5000; // 50% cap
+const FLASH_LOAN_FEE: u128 = 9; // 0.09% of the borrowed amount
+const MAX_FLASH_LOAN_FEE: u128 = 100; // 1% cap
+const KEEPER_TIP: u128 = 10; // 0.1% of the order paid to the filling keeper
+const MAX_KEEPER_TIP: u128 = 100; // 1% cap
+const DCA_SLIPPAGE: u128 = 100; // 1% below the oracle quote for DCA fills
+const MAX_DCA_SLIPPAGE: u128 = 1000; // 10% cap
+const SWAP_ESTIMATE_BUFFER: u128 = 100; // 1% over the oracle quote when sizing exact-out swaps
+const MAX_LEVERAGE: u128 = 100000; // 10x in basis points
+const MAX_LEVERAGE_LOOPS: u32 = 10; // Supply/borrow/swap rounds per leverage call
+const MIN_DCA_INTERVAL: u64 = 3600; // 1 hour
+const MIN_HEALTH_FACTOR: u128 = 1_000_000; // 100%; borrow LTV already leaves a buffer below liquidation
 const MAX_PRICE_AGE: u64 = 3600; // 1 hour in seconds
-const LIQUIDATION_THRESHOLD: u128 = 8000; // 80% in basis points
+const MAX_EMERGENCY_PRICE_DURATION: u64 = 604800; // Emergency prices expire within 7 days
+const MAX_PRICE_DEVIATION: u128 = 500; // 5% from the median before a source is rejected
+const CIRCUIT_BREAKER_MAX_CHANGE: u128 = 2000; // 20% move per window trips the breaker
+const CIRCUIT_BREAKER_WINDOW: u64 = 3600; // 1 hour
+const CIRCUIT_BREAKER_COOLDOWN: u64 = 3600; // 1 hour
+const TWAP_CAPACITY: u32 = 48; // Observations kept per asset
+const TWAP_MIN_INTERVAL: u64 = 60; // Seconds between stored slots, and between one keeper's recordings
+const TWAP_WINDOW: u64 = 1800; // Default averaging window (30 minutes)
+// Longest window the ring buffer always covers: the newest slot may still be refreshing,
+// so only the CAPACITY - 2 gaps between the older slots are guaranteed TWAP_MIN_INTERVAL long
+const MAX_TWAP_WINDOW: u64 = (TWAP_CAPACITY as u64 - 2) * TWAP_MIN_INTERVAL;
+const PRICE_HISTORY_LEN: u32 = 100; // Accepted prices kept per asset
+const HEALTH_HISTORY_LEN: u32 = 50; // Health factor snapshots kept per user
+const LIQUIDATION_THRESHOLD_BUFFER: u128 = 500; // Liquidation threshold sits 5% above borrow LTV
+const LIQUIDATION_BONUS: u128 = 500; // 5% default liquidation bonus
 const SECONDS_PER_DAY: u64 = 86400;
 
 // Stellar Testnet Addresses
@@ -84,7 +313,7 @@ const SUPPORTED_ASSETS: [(&str, &str, u32, u128, &str); 11] = [
     ("GDSTRSHXHGJ7ZIVRBXEYE5Q74XUVCUSEKEBR7UCHEUUEK72N7I7KJ6JH", "SHX", 6, 6500, "SHX"),
     ("GASBLVHS5FOABSDNW5SPPH3QRJYXY5JHA2AOA2QHH2FJLZBRXSG4SWXT", "WXT", 6, 6000, "WXT"),
     ("GBNLJIYH34UWO5YZFA3A3HD3N76R6DOI33N4JONUOHEEYZYCAYTEJ5AK", "RIO", 7, 6000, "RIO"),
-    // (Contract Address, Symbol, Decimals, Collateral Factor, DIA Symbol)
+    // (Contract Address, Symbol, Decimals, Borrow LTV, DIA Symbol)
 // (Issuer, Asset Code, Decimals, Collateral %, Display Name)
 
 ("GA5ZSE9EQLFZB5E34TRTFWNW5T76W2KQZ7ZYPZB2O2C3Y5QTKH7C5OL6", "USDC", 6, 8500, "USDC"),   // USDC
@@ -101,6 +330,12 @@ const SUPPORTED_ASSETS: [(&str, &str, u32, u128, &str); 11] = [
 
 ];
 
+// Long-tail assets that start in isolation mode
+const ISOLATED_ASSETS: [&str; 4] = ["VELO", "SHX", "WXT", "RIO"];
+const STABLECOIN_ASSETS: [&str; 2] = ["USDC", "USDT"];
+const ISOLATION_DEBT_CEILING: u128 = 100_000_000_000; // 100,000 in 6-decimal stablecoin units
+const STABLECOIN_EMODE: u32 = 1; // Default efficiency mode category for USDC/USDT
+
 // Oracle configuration
 #[contracttype]
 pub struct DIAOracleConfig {
@@ -118,13 +353,137 @@ pub struct DIAPriceData {
 
 
 #[contracttype]
+#[derive(Clone)]
 pub struct PriceSource {
-    pub source_type: String, // "oracle", "dex", "admin", "mock"
+    pub source_type: String, // "dia", "sep40", "dex_twap", "admin", "mock"
     pub price: u128,
     pub timestamp: u64,
     pub confidence: u32, // 0-100, higher = more reliable
 }
 
+// Oracle adapters the hub knows how to query
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum OracleSourceType {
+    Dia,
+    Sep40, // Any SEP-40 feed, e.g. Reflector
+    DexTwap, // Time-weighted average of recorded Soroswap observations
+    Admin,
+    Mock, // Hardcoded test prices, only served with the `mock-oracle` feature
+}
+
+// Configured price source for an asset
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct OracleSource {
+    pub source_type: OracleSourceType,
+    pub address: Option<Address>, // Oracle contract, None uses the hub default
+    pub confidence: u32, // 0-100, higher = more reliable
+    pub max_age: u64, // Seconds before this source's price is stale
+    pub decimals: u32, // Feed price decimals (DIA and SEP-40 sources)
+    pub feed_symbol: Option<Symbol>, // SEP-40 symbol feed (e.g. BTC), None queries by asset address
+}
+
+// Price circuit breaker settings
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct CircuitBreakerConfig {
+    pub max_change: u128, // In basis points allowed per window
+    pub window: u64, // Seconds per reference window
+    pub cooldown: u64, // Seconds a tripped asset stays frozen
+}
+
+// Last accepted price and circuit breaker state for an asset
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PriceStatus {
+    pub last_price: u128, // Last accepted price
+    pub last_update: u64,
+    pub reference_price: u128, // Accepted price at the start of the window
+    pub window_start: u64,
+    pub tripped: bool, // Price frozen and borrowing paused
+    pub tripped_at: u64,
+}
+
+// Freshness report for one configured price source
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SourceFreshness {
+    pub source_type: OracleSourceType,
+    pub available: bool, // Source returned a price
+    pub last_update: u64,
+    pub age: u64, // Seconds since last_update
+    pub max_age: u64,
+    pub is_fresh: bool,
+}
+
+// Accepted price history entry
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PricePoint {
+    pub timestamp: u64,
+    pub price: u128,
+    pub source: String, // Single source label, or the aggregation mode
+}
+
+// User health factor history entry
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct HealthSnapshot {
+    pub timestamp: u64,
+    pub health_factor: u128,
+}
+
+// Spot DEX price observation for the TWAP oracle
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TwapObservation {
+    pub timestamp: u64,
+    pub price: u128, // Spot Soroswap price when observed
+    pub cumulative_price: u128, // Sum of price * seconds up to this observation
+}
+
+// Ring buffer of TWAP observations for an asset
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct TwapBuffer {
+    pub observations: Vec<TwapObservation>,
+    pub next_index: u32, // Slot written next once the buffer is full
+}
+
+// How fresh source prices are combined into one price
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum AggregationMode {
+    Median, // Median of agreeing sources
+    WeightedMean, // Confidence-weighted mean of agreeing sources
+}
+
+// Price aggregation settings
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct AggregationConfig {
+    pub mode: AggregationMode,
+    pub min_sources: u32, // Sources that must agree for a price to be accepted
+    pub max_deviation: u128, // In basis points from the median; beyond this a source is an outlier
+}
+
+// SEP-40 asset identifier
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Sep40Asset {
+    Stellar(Address),
+    Other(Symbol),
+}
+
+// SEP-40 lastprice() result
+#[contracttype]
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct PriceData {
+    pub price: i128, // Price in the feed's decimals
+    pub timestamp: u64,
+}
+
 #[contracttype]
 pub struct DexPriceInfo {
     pub price: u128,
@@ -150,13 +509,20 @@ impl StellarDeFiHub {
         // Initialize supported assets
         Self::initialize_assets(&env);
         
+        // Initialize efficiency mode categories
+        Self::initialize_emode(&env);
+        
         // Initialize reward system
         Self::initialize_rewards(&env);
         
+        // Initialize protocol fee configuration
+        Self::initialize_fees(&env);
+        
         env.storage().instance().set(&symbol_short!("init"), &true);
     }
 
     /// Swap tokens using Soroswap with fee collection
+    #[allow(clippy::too_many_arguments)]
     pub fn swap_tokens(
         env: Env,
         user: Address,
@@ -165,6 +531,7 @@ impl StellarDeFiHub {
         amount_in: u128,
         min_amount_out: u128,
         deadline: u64,
+        referrer: Option<Address>,
     ) -> u128 {
         user.require_auth();
         
@@ -175,188 +542,1331 @@ impl StellarDeFiHub {
         // Check deadline
         assert!(env.ledger().timestamp() <= deadline, "Transaction expired");
         
-        // Calculate protocol fee
-        let fee_amount = (amount_in * PROTOCOL_FEE) / 10000;
-        let swap_amount = amount_in - fee_amount;
+        // Record DEX observations before our own swap moves the pool
+        Self::record_twap_observation(&env, &token_a);
+        Self::record_twap_observation(&env, &token_b);
         
         // Transfer tokens from user
         Self::transfer_from_user(&env, &token_a, &user, &env.current_contract_address(), amount_in);
         
-        // Perform swap via Soroswap
-        let amount_out = Self::execute_soroswap(&env, &token_a, &token_b, swap_amount, min_amount_out);
-        
-        // Add fee to reward pool
-        Self::add_to_reward_pool(&env, &token_a, fee_amount);
+        // Perform swap via Soroswap, collecting the protocol fee
+        let swap = SwapParams {
+            token_a,
+            token_b: token_b.clone(),
+            amount_in,
+            min_amount_out,
+        };
+        let (amount_out, _) = Self::execute_swap_with_fees(&env, &user, &swap, &referrer, symbol_short!("market"));
         
         // Transfer swapped tokens to user
         Self::transfer_to_user(&env, &token_b, &user, amount_out);
         
-        // Emit swap event
-        env.events().publish(
-            (symbol_short!("swap"), &user),
-            (token_a, token_b, amount_in, amount_out, fee_amount)
-        );
-        
         amount_out
     }
 
-    /// Supply assets to Blend lending pool
-    pub fn supply_to_blend(
+    /// Create a limit order, escrowing token_a until the target price is reached
+    pub fn create_limit_order(
         env: Env,
         user: Address,
-        asset: Address,
-        amount: u128,
-    ) -> Address {
+        token_a: Address,
+        token_b: Address,
+        amount_in: u128,
+        target_price: u128,
+        expiry: u64,
+    ) -> u64 {
         user.require_auth();
         
-        // Validate asset is supported
-        assert!(Self::is_asset_supported(&env, &asset), "Asset not supported");
+        assert!(Self::is_asset_supported(&env, &token_a), "Token A not supported");
+        assert!(Self::is_asset_supported(&env, &token_b), "Token B not supported");
+        assert!(amount_in > 0 && target_price > 0, "Invalid order");
+        assert!(expiry > env.ledger().timestamp(), "Order already expired");
         
-        // Get Blend pool for asset
-        let blend_pool = Self::get_or_create_blend_pool(&env, &asset);
+        // Escrow token_a in the hub
+        Self::transfer_from_user(&env, &token_a, &user, &env.current_contract_address(), amount_in);
         
-        // Transfer asset from user
-        Self::transfer_from_user(&env, &asset, &user, &env.current_contract_address(), amount);
+        let order_id: u64 = env.storage().instance().get(&symbol_short!("ord_next")).unwrap_or(1);
+        env.storage().instance().set(&symbol_short!("ord_next"), &(order_id + 1));
         
-        // Supply to Blend pool and receive bTokens
-        let btokens_received = Self::supply_to_blend_pool(&env, &blend_pool, &asset, amount);
+        let order = LimitOrder {
+            id: order_id,
+            owner: user.clone(),
+            token_a: token_a.clone(),
+            token_b: token_b.clone(),
+            amount_in,
+            target_price,
+            expiry,
+            status: OrderStatus::Open,
+            created_at: env.ledger().timestamp(),
+        };
+        env.storage().persistent().set(&(symbol_short!("order"), order_id), &order);
         
-        // Update user position
-        Self::update_user_supply_position(&env, &user, &asset, amount);
+        let key = (symbol_short!("u_orders"), user.clone());
+        let mut open_orders: Vec<u64> = env.storage().persistent().get(&key).unwrap_or(Vec::new(&env));
+        open_orders.push_back(order_id);
+        env.storage().persistent().set(&key, &open_orders);
         
-        // Transfer bTokens to user
-        Self::transfer_to_user(&env, &blend_pool.reserve_asset, &user, btokens_received);
+        env.events().publish(
+            (symbol_short!("ord_new"), &user),
+            (order_id, token_a, token_b, amount_in, target_price, expiry)
+        );
+        
+        order_id
+    }
+
+    /// Fill an open limit order once the Soroswap quote meets its target (callable by any keeper)
+    pub fn fill_order(env: Env, keeper: Address, order_id: u64) -> u128 {
+        keeper.require_auth();
+        
+        let mut order = Self::get_order(env.clone(), order_id);
+        assert!(order.status == OrderStatus::Open, "Order not open");
+        assert!(env.ledger().timestamp() <= order.expiry, "Order expired");
+        
+        // Keeper tip comes out of the escrowed amount
+        let tip = (order.amount_in * Self::get_keeper_tip(env.clone())) / 10000;
+        let swap_input = order.amount_in - tip;
+        
+        // Target price is per whole escrowed token_a, net of protocol fee; the keeper
+        // tip has to come out of price improvement, not out of the owner's target
+        let precision = Self::get_price_precision(&env, &order.token_a)
+            .unwrap_or_else(|_| panic!("Asset not supported"));
+        let min_amount_out = (order.amount_in * order.target_price) / precision;
+        
+        // Check the current quote before committing to the swap
+        let fee = Self::get_effective_fee(env.clone(), order.owner.clone(), order.token_a.clone(), order.token_b.clone());
+        let quote_amount = swap_input - (swap_input * fee) / 10000;
+        let quote = Self::simulate_dex_swap(&env, &order.token_a, &order.token_b, quote_amount)
+            .unwrap_or_else(|| panic!("Quote unavailable"));
+        assert!(quote.price >= min_amount_out, "Target price not reached");
+        
+        let swap = SwapParams {
+            token_a: order.token_a.clone(),
+            token_b: order.token_b.clone(),
+            amount_in: swap_input,
+            min_amount_out,
+        };
+        let (amount_out, fee_amount) = Self::execute_swap_with_fees(&env, &order.owner, &swap, &None, symbol_short!("limit"));
+        
+        order.status = OrderStatus::Filled;
+        env.storage().persistent().set(&(symbol_short!("order"), order_id), &order);
+        Self::remove_open_order(&env, &order.owner, order_id);
+        
+        // Pay keeper and order owner
+        if tip > 0 {
+            Self::transfer_to_user(&env, &order.token_a, &keeper, tip);
+        }
+        Self::transfer_to_user(&env, &order.token_b, &order.owner, amount_out);
         
         env.events().publish(
-            (symbol_short!("supply"), &user),
-            (asset, amount, btokens_received)
+            (symbol_short!("ord_fill"), &order.owner),
+            (order_id, keeper, amount_out, fee_amount, tip)
         );
         
-        blend_pool.reserve_asset
+        amount_out
     }
 
-    /// Borrow assets from Blend with collateral check
-    pub fn borrow_from_blend(
-        env: Env,
-        user: Address,
-        asset: Address,
-        amount: u128,
-    ) {
+    /// Cancel an open limit order and return the escrowed tokens
+    pub fn cancel_order(env: Env, user: Address, order_id: u64) -> u128 {
         user.require_auth();
         
-        // Validate asset
-        assert!(Self::is_asset_supported(&env, &asset), "Asset not supported");
+        let mut order = Self::get_order(env.clone(), order_id);
+        assert_eq!(order.owner, user, "Only order owner can cancel");
+        assert!(order.status == OrderStatus::Open, "Order not open");
         
-        // Check user's collateral health BEFORE borrowing
-        let health_factor = Self::calculate_health_factor(env.clone(), user.clone(), Some((asset.clone(), amount)));
-        assert!(health_factor >= 1_200_000, "Insufficient collateral for borrow"); // 120% minimum
+        order.status = OrderStatus::Cancelled;
+        env.storage().persistent().set(&(symbol_short!("order"), order_id), &order);
+        Self::remove_open_order(&env, &user, order_id);
         
-        // Get Blend pool
-        let blend_pool = Self::get_or_create_blend_pool(&env, &asset);
+        // Return escrow
+        Self::transfer_to_user(&env, &order.token_a, &user, order.amount_in);
         
-        // Borrow from Blend
-        Self::borrow_from_blend_pool(&env, &blend_pool, &asset, amount);
+        env.events().publish(
+            (symbol_short!("ord_cncl"), &user),
+            (order_id, order.amount_in)
+        );
         
-        // Update user position
-        Self::update_user_borrow_position(&env, &user, &asset, amount);
+        order.amount_in
+    }
+
+    /// Get a limit order by id
+    pub fn get_order(env: Env, order_id: u64) -> LimitOrder {
+        env.storage()
+            .persistent()
+            .get(&(symbol_short!("order"), order_id))
+            .unwrap_or_else(|| panic!("Order not found"))
+    }
+
+    /// Get a user's open limit orders
+    pub fn get_open_orders(env: Env, user: Address) -> Vec<LimitOrder> {
+        let order_ids: Vec<u64> = env.storage()
+            .persistent()
+            .get(&(symbol_short!("u_orders"), user))
+            .unwrap_or(Vec::new(&env));
         
-        // Transfer borrowed asset to user
-        Self::transfer_to_user(&env, &asset, &user, amount);
+        let mut orders = Vec::new(&env);
+        for order_id in order_ids.iter() {
+            orders.push_back(Self::get_order(env.clone(), order_id));
+        }
+        orders
+    }
+
+    /// Admin function to set the keeper tip paid on order fills
+    pub fn set_keeper_tip(env: Env, admin: Address, tip: u128) {
+        admin.require_auth();
+        
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can set keeper tip");
+        assert!(tip <= MAX_KEEPER_TIP, "Keeper tip exceeds maximum");
+        
+        env.storage().instance().set(&symbol_short!("kpr_tip"), &tip);
         
         env.events().publish(
-            (symbol_short!("borrow"), &user),
-            (asset, amount, health_factor)
+            (symbol_short!("kpr_tip"), &admin),
+            tip
         );
     }
 
-    /// Stake bTokens to earn protocol fees
-    pub fn stake_btokens(
+    /// Get the keeper tip (in basis points) paid on order fills
+    pub fn get_keeper_tip(env: Env) -> u128 {
+        env.storage()
+            .instance()
+            .get(&symbol_short!("kpr_tip"))
+            .unwrap_or(KEEPER_TIP)
+    }
+
+    /// Create a dollar-cost-averaging schedule, escrowing the full token_a amount
+    pub fn create_dca(
         env: Env,
         user: Address,
-        btoken: Address,
-        amount: u128,
-    ) {
+        token_a: Address,
+        token_b: Address,
+        total_amount: u128,
+        per_interval: u128,
+        interval_secs: u64,
+    ) -> u64 {
         user.require_auth();
         
-        // Transfer bTokens from user
-        Self::transfer_from_user(&env, &btoken, &user, &env.current_contract_address(), amount);
+        assert!(Self::is_asset_supported(&env, &token_a), "Token A not supported");
+        assert!(Self::is_asset_supported(&env, &token_b), "Token B not supported");
+        assert!(per_interval > 0 && per_interval <= total_amount, "Invalid DCA amounts");
+        assert!(interval_secs >= MIN_DCA_INTERVAL, "DCA interval too short");
         
-        // Update user rewards before changing stake
-        Self::update_user_rewards(&env, &user, &btoken);
+        // Escrow token_a in the hub
+        Self::transfer_from_user(&env, &token_a, &user, &env.current_contract_address(), total_amount);
         
-        // Update staking position
-        Self::update_staking_position(&env, &user, &btoken, amount, true);
+        let dca_id: u64 = env.storage().instance().get(&symbol_short!("dca_next")).unwrap_or(1);
+        env.storage().instance().set(&symbol_short!("dca_next"), &(dca_id + 1));
         
-        // Update staking pool
-        Self::update_staking_pool(&env, &btoken, amount, true);
+        let schedule = DcaSchedule {
+            id: dca_id,
+            owner: user.clone(),
+            token_a: token_a.clone(),
+            token_b: token_b.clone(),
+            total_amount,
+            remaining: total_amount,
+            per_interval,
+            interval_secs,
+            next_execution: env.ledger().timestamp(),
+            executions: 0,
+            total_received: 0,
+            status: DcaStatus::Active,
+        };
+        env.storage().persistent().set(&(symbol_short!("dca"), dca_id), &schedule);
+        
+        let key = (symbol_short!("u_dca"), user.clone());
+        let mut schedules: Vec<u64> = env.storage().persistent().get(&key).unwrap_or(Vec::new(&env));
+        schedules.push_back(dca_id);
+        env.storage().persistent().set(&key, &schedules);
         
         env.events().publish(
-            (symbol_short!("stake"), &user),
-            (btoken, amount)
+            (symbol_short!("dca_new"), &user),
+            (dca_id, token_a, token_b, total_amount, per_interval, interval_secs)
+        );
+        
+        dca_id
+    }
+
+    /// Execute the next interval of a DCA schedule (callable by any keeper)
+    pub fn execute_dca(env: Env, keeper: Address, dca_id: u64) -> u128 {
+        keeper.require_auth();
+        
+        let mut schedule = Self::get_dca(env.clone(), dca_id);
+        assert!(schedule.status == DcaStatus::Active, "DCA not active");
+        assert!(env.ledger().timestamp() >= schedule.next_execution, "DCA interval not reached");
+        
+        let amount = schedule.per_interval.min(schedule.remaining);
+        let tip = (amount * Self::get_keeper_tip(env.clone())) / 10000;
+        let swap_input = amount - tip;
+        
+        // Minimum output comes from the oracle, not from the pool being swapped against
+        let fee = Self::get_effective_fee(env.clone(), schedule.owner.clone(), schedule.token_a.clone(), schedule.token_b.clone());
+        let quote_amount = swap_input - (swap_input * fee) / 10000;
+        let oracle_out = Self::oracle_quote(&env, &schedule.token_a, &schedule.token_b, quote_amount)
+            .unwrap_or_else(|e| panic_with_error!(&env, e));
+        let min_amount_out = (oracle_out * (10000 - Self::get_dca_slippage(env.clone()))) / 10000;
+        
+        let swap = SwapParams {
+            token_a: schedule.token_a.clone(),
+            token_b: schedule.token_b.clone(),
+            amount_in: swap_input,
+            min_amount_out,
+        };
+        let (amount_out, _) = Self::execute_swap_with_fees(&env, &schedule.owner, &swap, &None, symbol_short!("dca"));
+        
+        schedule.remaining -= amount;
+        schedule.executions += 1;
+        schedule.total_received += amount_out;
+        schedule.next_execution = env.ledger().timestamp() + schedule.interval_secs;
+        if schedule.remaining == 0 {
+            schedule.status = DcaStatus::Completed;
+            Self::remove_user_dca(&env, &schedule.owner, dca_id);
+        }
+        env.storage().persistent().set(&(symbol_short!("dca"), dca_id), &schedule);
+        
+        // Pay keeper and schedule owner
+        if tip > 0 {
+            Self::transfer_to_user(&env, &schedule.token_a, &keeper, tip);
+        }
+        Self::transfer_to_user(&env, &schedule.token_b, &schedule.owner, amount_out);
+        
+        env.events().publish(
+            (symbol_short!("dca_exec"), &schedule.owner),
+            (dca_id, keeper, amount, amount_out, schedule.remaining)
         );
+        
+        amount_out
     }
 
-    /// Unstake bTokens and claim rewards
-    pub fn unstake_and_claim(
-        env: Env,
-        user: Address,
-        btoken: Address,
-        amount: u128,
-    ) -> u128 {
+    /// Pause an active DCA schedule
+    pub fn pause_dca(env: Env, user: Address, dca_id: u64) {
         user.require_auth();
         
-        // Update user rewards before unstaking
-        Self::update_user_rewards(&env, &user, &btoken);
+        let mut schedule = Self::get_dca(env.clone(), dca_id);
+        assert_eq!(schedule.owner, user, "Only schedule owner can pause");
+        assert!(schedule.status == DcaStatus::Active, "DCA not active");
         
-        // Calculate claimable rewards
-        let rewards = Self::get_claimable_rewards(&env, &user);
+        schedule.status = DcaStatus::Paused;
+        env.storage().persistent().set(&(symbol_short!("dca"), dca_id), &schedule);
         
-        // Update staking position
-        Self::update_staking_position(&env, &user, &btoken, amount, false);
+        env.events().publish(
+            (symbol_short!("dca_pause"), &user),
+            dca_id
+        );
+    }
+
+    /// Resume a paused DCA schedule
+    pub fn resume_dca(env: Env, user: Address, dca_id: u64) {
+        user.require_auth();
         
-        // Update staking pool
-        Self::update_staking_pool(&env, &btoken, amount, false);
+        let mut schedule = Self::get_dca(env.clone(), dca_id);
+        assert_eq!(schedule.owner, user, "Only schedule owner can resume");
+        assert!(schedule.status == DcaStatus::Paused, "DCA not paused");
         
-        // Transfer bTokens back to user
-        Self::transfer_to_user(&env, &btoken, &user, amount);
+        schedule.status = DcaStatus::Active;
+        schedule.next_execution = schedule.next_execution.max(env.ledger().timestamp());
+        env.storage().persistent().set(&(symbol_short!("dca"), dca_id), &schedule);
         
-        // Transfer rewards if any (in collected fee tokens)
-        if rewards > 0 {
-            Self::distribute_rewards(&env, &user, rewards);
+        env.events().publish(
+            (symbol_short!("dca_resum"), &user),
+            dca_id
+        );
+    }
+
+    /// Cancel a DCA schedule and withdraw the remaining escrow
+    pub fn cancel_dca(env: Env, user: Address, dca_id: u64) -> u128 {
+        user.require_auth();
+        
+        let mut schedule = Self::get_dca(env.clone(), dca_id);
+        assert_eq!(schedule.owner, user, "Only schedule owner can cancel");
+        assert!(
+            schedule.status == DcaStatus::Active || schedule.status == DcaStatus::Paused,
+            "DCA already closed"
+        );
+        
+        let remaining = schedule.remaining;
+        schedule.remaining = 0;
+        schedule.status = DcaStatus::Cancelled;
+        env.storage().persistent().set(&(symbol_short!("dca"), dca_id), &schedule);
+        Self::remove_user_dca(&env, &user, dca_id);
+        
+        // Return escrow
+        if remaining > 0 {
+            Self::transfer_to_user(&env, &schedule.token_a, &user, remaining);
         }
         
         env.events().publish(
-            (symbol_short!("unstake"), &user),
-            (btoken, amount, rewards)
+            (symbol_short!("dca_cncl"), &user),
+            (dca_id, remaining)
         );
         
-        rewards
+        remaining
     }
 
-    /// Get user's position across all protocols
-    pub fn get_user_position(env: Env, user: Address) -> UserPosition {
+    /// Get a DCA schedule by id
+    pub fn get_dca(env: Env, dca_id: u64) -> DcaSchedule {
         env.storage()
             .persistent()
-            .get(&(symbol_short!("pos"), user))
-            .unwrap_or(UserPosition {
-                supplied_assets: Map::new(&env),
-                borrowed_assets: Map::new(&env),
-                staked_lp_tokens: Map::new(&env),
-                rewards_earned: 0,
-                last_reward_update: env.ledger().timestamp(),
-            })
+            .get(&(symbol_short!("dca"), dca_id))
+            .unwrap_or_else(|| panic!("DCA schedule not found"))
     }
 
-    /// Calculate user's health factor for borrowing
+    /// Get a user's active and paused DCA schedules
+    pub fn get_user_dcas(env: Env, user: Address) -> Vec<DcaSchedule> {
+        let dca_ids: Vec<u64> = env.storage()
+            .persistent()
+            .get(&(symbol_short!("u_dca"), user))
+            .unwrap_or(Vec::new(&env));
+        
+        let mut schedules = Vec::new(&env);
+        for dca_id in dca_ids.iter() {
+            schedules.push_back(Self::get_dca(env.clone(), dca_id));
+        }
+        schedules
+    }
+
+    /// Admin function to set the slippage allowed below the oracle quote on DCA fills
+    pub fn set_dca_slippage(env: Env, admin: Address, slippage: u128) {
+        admin.require_auth();
+        
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can set DCA slippage");
+        assert!(slippage <= MAX_DCA_SLIPPAGE, "Slippage exceeds maximum");
+        
+        env.storage().instance().set(&symbol_short!("dca_slip"), &slippage);
+        
+        env.events().publish(
+            (symbol_short!("dca_slip"), &admin),
+            slippage
+        );
+    }
+
+    /// Get the slippage (in basis points) allowed below the oracle quote on DCA fills
+    pub fn get_dca_slippage(env: Env) -> u128 {
+        env.storage()
+            .instance()
+            .get(&symbol_short!("dca_slip"))
+            .unwrap_or(DCA_SLIPPAGE)
+    }
+
+    /// Register a stop-loss or take-profit order against the oracle price of an asset
+    pub fn create_conditional_order(
+        env: Env,
+        user: Address,
+        params: ConditionalOrderParams,
+    ) -> u64 {
+        user.require_auth();
+        
+        let ConditionalOrderParams { asset, target_asset, amount, trigger_price, trigger, backing, max_slippage } = params;
+        assert!(Self::is_asset_supported(&env, &asset), "Asset not supported");
+        assert!(Self::is_asset_supported(&env, &target_asset), "Target asset not supported");
+        assert!(amount > 0 && trigger_price > 0, "Invalid order");
+        assert!(max_slippage <= 10000, "Invalid slippage");
+        
+        match backing {
+            OrderBacking::Wallet => {
+                // Escrow the asset in the hub
+                Self::transfer_from_user(&env, &asset, &user, &env.current_contract_address(), amount);
+            }
+            OrderBacking::Collateral => {
+                let position = Self::get_user_position(env.clone(), user.clone());
+                let supplied = position.supplied_assets.get(asset.clone()).unwrap_or(0);
+                assert!(supplied >= amount, "Insufficient supplied collateral");
+            }
+        }
+        
+        let order_id: u64 = env.storage().instance().get(&symbol_short!("cond_next")).unwrap_or(1);
+        env.storage().instance().set(&symbol_short!("cond_next"), &(order_id + 1));
+        
+        let order = ConditionalOrder {
+            id: order_id,
+            owner: user.clone(),
+            asset: asset.clone(),
+            target_asset: target_asset.clone(),
+            amount,
+            trigger_price,
+            trigger: trigger.clone(),
+            backing,
+            max_slippage,
+            status: OrderStatus::Open,
+            created_at: env.ledger().timestamp(),
+        };
+        env.storage().persistent().set(&(symbol_short!("cond"), order_id), &order);
+        
+        let key = (symbol_short!("u_cond"), user.clone());
+        let mut open_orders: Vec<u64> = env.storage().persistent().get(&key).unwrap_or(Vec::new(&env));
+        open_orders.push_back(order_id);
+        env.storage().persistent().set(&key, &open_orders);
+        
+        env.events().publish(
+            (symbol_short!("cond_new"), &user),
+            (order_id, asset, target_asset, amount, trigger_price, trigger)
+        );
+        
+        order_id
+    }
+
+    /// Execute a conditional order once its oracle trigger is hit (callable by any keeper)
+    pub fn execute_conditional_order(env: Env, keeper: Address, order_id: u64) -> u128 {
+        keeper.require_auth();
+        
+        let mut order = Self::get_conditional_order(env.clone(), order_id);
+        assert!(order.status == OrderStatus::Open, "Order not open");
+        
+        // Check trigger against the oracle price
+        let price = Self::get_asset_price_safe(&env, &order.asset)
+            .unwrap_or_else(|e| panic_with_error!(&env, e));
+        let triggered = match order.trigger {
+            TriggerType::StopLoss => price <= order.trigger_price,
+            TriggerType::TakeProfit => price >= order.trigger_price,
+        };
+        assert!(triggered, "Trigger price not reached");
+        
+        // Collateral-backed orders pull funds out of Blend now; the owner must have
+        // approved the hub to take back the matching bTokens
+        if order.backing == OrderBacking::Collateral {
+            let position = Self::get_user_position(env.clone(), order.owner.clone());
+            let supplied = position.supplied_assets.get(order.asset.clone()).unwrap_or(0);
+            assert!(supplied >= order.amount, "Insufficient supplied collateral");
+            Self::withdraw_for_user(&env, &order.owner, &order.asset, order.amount);
+        }
+        
+        let tip = (order.amount * Self::get_keeper_tip(env.clone())) / 10000;
+        let swap_input = order.amount - tip;
+        
+        // Minimum output from the oracle quote, less the owner's slippage tolerance
+        let fee = Self::get_effective_fee(env.clone(), order.owner.clone(), order.asset.clone(), order.target_asset.clone());
+        let quote_amount = swap_input - (swap_input * fee) / 10000;
+        let oracle_out = Self::oracle_quote(&env, &order.asset, &order.target_asset, quote_amount)
+            .unwrap_or_else(|e| panic_with_error!(&env, e));
+        let min_amount_out = (oracle_out * (10000 - order.max_slippage)) / 10000;
+        
+        let kind = match order.trigger {
+            TriggerType::StopLoss => symbol_short!("stop_loss"),
+            TriggerType::TakeProfit => symbol_short!("take_prft"),
+        };
+        let swap = SwapParams {
+            token_a: order.asset.clone(),
+            token_b: order.target_asset.clone(),
+            amount_in: swap_input,
+            min_amount_out,
+        };
+        let (amount_out, _) = Self::execute_swap_with_fees(&env, &order.owner, &swap, &None, kind);
+        
+        order.status = OrderStatus::Filled;
+        env.storage().persistent().set(&(symbol_short!("cond"), order_id), &order);
+        Self::remove_conditional_order(&env, &order.owner, order_id);
+        
+        // Collateral-backed proceeds repay outstanding debt in the target asset first
+        let mut to_owner = amount_out;
+        if order.backing == OrderBacking::Collateral {
+            let position = Self::get_user_position(env.clone(), order.owner.clone());
+            let debt = position.borrowed_assets.get(order.target_asset.clone()).unwrap_or(0);
+            let repay_amount = debt.min(amount_out);
+            if repay_amount > 0 {
+                Self::repay_for_user(&env, &order.owner, &order.target_asset, repay_amount);
+                to_owner -= repay_amount;
+            }
+            
+            let health_factor = Self::get_liquidation_health(env.clone(), order.owner.clone());
+            assert!(health_factor >= MIN_HEALTH_FACTOR, "Order would leave position liquidatable");
+            Self::record_health_snapshot(&env, &order.owner);
+        }
+        
+        // Pay keeper and order owner
+        if tip > 0 {
+            Self::transfer_to_user(&env, &order.asset, &keeper, tip);
+        }
+        if to_owner > 0 {
+            Self::transfer_to_user(&env, &order.target_asset, &order.owner, to_owner);
+        }
+        
+        env.events().publish(
+            (symbol_short!("cond_exec"), &order.owner),
+            (order_id, keeper, price, amount_out, amount_out - to_owner)
+        );
+        
+        amount_out
+    }
+
+    /// Cancel an open conditional order, returning any wallet escrow
+    pub fn cancel_conditional_order(env: Env, user: Address, order_id: u64) {
+        user.require_auth();
+        
+        let mut order = Self::get_conditional_order(env.clone(), order_id);
+        assert_eq!(order.owner, user, "Only order owner can cancel");
+        assert!(order.status == OrderStatus::Open, "Order not open");
+        
+        order.status = OrderStatus::Cancelled;
+        env.storage().persistent().set(&(symbol_short!("cond"), order_id), &order);
+        Self::remove_conditional_order(&env, &user, order_id);
+        
+        if order.backing == OrderBacking::Wallet {
+            Self::transfer_to_user(&env, &order.asset, &user, order.amount);
+        }
+        
+        env.events().publish(
+            (symbol_short!("cond_cncl"), &user),
+            order_id
+        );
+    }
+
+    /// Get a conditional order by id
+    pub fn get_conditional_order(env: Env, order_id: u64) -> ConditionalOrder {
+        env.storage()
+            .persistent()
+            .get(&(symbol_short!("cond"), order_id))
+            .unwrap_or_else(|| panic!("Order not found"))
+    }
+
+    /// Get a user's open conditional orders
+    pub fn get_conditional_orders(env: Env, user: Address) -> Vec<ConditionalOrder> {
+        let order_ids: Vec<u64> = env.storage()
+            .persistent()
+            .get(&(symbol_short!("u_cond"), user))
+            .unwrap_or(Vec::new(&env));
+        
+        let mut orders = Vec::new(&env);
+        for order_id in order_ids.iter() {
+            orders.push_back(Self::get_conditional_order(env.clone(), order_id));
+        }
+        orders
+    }
+
+    /// Supply assets to Blend lending pool
+    pub fn supply_to_blend(
+        env: Env,
+        user: Address,
+        asset: Address,
+        amount: u128,
+    ) -> Address {
+        user.require_auth();
+        
+        // Validate asset is supported
+        assert!(Self::is_asset_supported(&env, &asset), "Asset not supported");
+        
+        Self::record_twap_observation(&env, &asset);
+        
+        // Transfer asset from user
+        Self::transfer_from_user(&env, &asset, &user, &env.current_contract_address(), amount);
+        
+        // Supply to Blend pool and update user position
+        let (blend_pool, btokens_received) = Self::supply_for_user(&env, &user, &asset, amount);
+        
+        // Transfer bTokens to user
+        Self::transfer_to_user(&env, &blend_pool.reserve_asset, &user, btokens_received);
+        
+        Self::record_health_snapshot(&env, &user);
+        
+        env.events().publish(
+            (symbol_short!("supply"), &user),
+            (asset, amount, btokens_received)
+        );
+        
+        blend_pool.reserve_asset
+    }
+
+    /// Borrow assets from Blend with collateral check
+    pub fn borrow_from_blend(
+        env: Env,
+        user: Address,
+        asset: Address,
+        amount: u128,
+    ) {
+        user.require_auth();
+        
+        // Validate asset
+        assert!(Self::is_asset_supported(&env, &asset), "Asset not supported");
+        
+        Self::record_twap_observation(&env, &asset);
+        
+        // Check user's collateral health BEFORE borrowing
+        let health_factor = Self::calculate_health_factor(env.clone(), user.clone(), Some((asset.clone(), amount)));
+        assert!(health_factor >= MIN_HEALTH_FACTOR, "Insufficient collateral for borrow");
+        
+        // Borrow from Blend and update user position
+        Self::borrow_for_user(&env, &user, &asset, amount);
+        
+        // Transfer borrowed asset to user
+        Self::transfer_to_user(&env, &asset, &user, amount);
+        
+        Self::record_health_snapshot(&env, &user);
+        
+        env.events().publish(
+            (symbol_short!("borrow"), &user),
+            (asset, amount, health_factor)
+        );
+    }
+
+    /// Withdraw supplied assets from Blend, keeping the position healthy.
+    /// The matching bTokens are taken back from the user, so the hub must be approved to move them.
+    pub fn withdraw_from_blend(
+        env: Env,
+        user: Address,
+        asset: Address,
+        amount: u128,
+    ) -> u128 {
+        user.require_auth();
+        
+        assert!(Self::is_asset_supported(&env, &asset), "Asset not supported");
+        
+        Self::record_twap_observation(&env, &asset);
+        
+        // Reclaim bTokens, withdraw from Blend and update user position
+        let btokens_returned = Self::withdraw_for_user(&env, &user, &asset, amount);
+        
+        // Remaining collateral must still cover existing debt
+        let health_factor = Self::calculate_health_factor(env.clone(), user.clone(), None);
+        assert!(health_factor >= MIN_HEALTH_FACTOR, "Insufficient collateral for withdrawal");
+        
+        // Transfer withdrawn asset to user
+        Self::transfer_to_user(&env, &asset, &user, amount);
+        
+        Self::record_health_snapshot(&env, &user);
+        
+        env.events().publish(
+            (symbol_short!("withdraw"), &user),
+            (asset, amount, btokens_returned, health_factor)
+        );
+        
+        btokens_returned
+    }
+
+    /// Rotate supplied collateral into another asset without unwinding the position.
+    /// bTokens for the withdrawn collateral are taken back; new bTokens are issued for the re-supply.
+    pub fn swap_collateral(
+        env: Env,
+        user: Address,
+        from_asset: Address,
+        to_asset: Address,
+        amount: u128,
+        min_out: u128,
+    ) -> u128 {
+        user.require_auth();
+        
+        assert!(Self::is_asset_supported(&env, &from_asset), "Asset not supported");
+        assert!(Self::is_asset_supported(&env, &to_asset), "Asset not supported");
+        assert!(from_asset != to_asset, "Assets must differ");
+        assert!(amount > 0, "Amount must be positive");
+        
+        Self::record_twap_observation(&env, &from_asset);
+        Self::record_twap_observation(&env, &to_asset);
+        
+        // Withdraw, swap and re-supply; only the final position has to be healthy
+        let btokens_returned = Self::withdraw_for_user(&env, &user, &from_asset, amount);
+        let amount_out = Self::execute_soroswap(&env, &from_asset, &to_asset, amount, min_out);
+        assert!(amount_out >= min_out, "Slippage exceeded");
+        let (blend_pool, btokens_received) = Self::supply_for_user(&env, &user, &to_asset, amount_out);
+        
+        let health_factor = Self::calculate_health_factor(env.clone(), user.clone(), None);
+        assert!(health_factor >= MIN_HEALTH_FACTOR, "Insufficient collateral after swap");
+        
+        // Transfer bTokens for the new supply to user
+        Self::transfer_to_user(&env, &blend_pool.reserve_asset, &user, btokens_received);
+        
+        Self::record_health_snapshot(&env, &user);
+        
+        env.events().publish(
+            (symbol_short!("coll_swap"), &user),
+            (from_asset, to_asset, amount, amount_out, btokens_returned, btokens_received, health_factor)
+        );
+        
+        amount_out
+    }
+
+    /// Refinance debt into another asset: borrow the new asset, swap it and repay the old debt
+    pub fn swap_debt(
+        env: Env,
+        user: Address,
+        from_debt_asset: Address,
+        to_debt_asset: Address,
+        amount: u128,
+        max_new_debt: u128,
+    ) -> u128 {
+        user.require_auth();
+        
+        assert!(Self::is_asset_supported(&env, &from_debt_asset), "Asset not supported");
+        assert!(Self::is_asset_supported(&env, &to_debt_asset), "Asset not supported");
+        assert!(from_debt_asset != to_debt_asset, "Assets must differ");
+        assert!(amount > 0, "Amount must be positive");
+        
+        let position = Self::get_user_position(env.clone(), user.clone());
+        let current_debt = position.borrowed_assets.get(from_debt_asset.clone()).unwrap_or(0);
+        assert!(amount <= current_debt, "Amount exceeds debt");
+        
+        Self::record_twap_observation(&env, &from_debt_asset);
+        Self::record_twap_observation(&env, &to_debt_asset);
+        
+        // Size the new borrow from oracle prices, bounded by the user's limit
+        let new_debt = Self::estimate_amount_in(&env, &to_debt_asset, &from_debt_asset, amount)
+            .unwrap_or_else(|e| panic_with_error!(&env, e));
+        assert!(new_debt <= max_new_debt, "New debt exceeds maximum");
+        
+        // Borrow, swap and repay; only the final health factor is enforced
+        Self::check_borrow_rules(&env, &user, &to_debt_asset, new_debt);
+        Self::borrow_for_user(&env, &user, &to_debt_asset, new_debt);
+        let amount_out = Self::execute_soroswap(&env, &to_debt_asset, &from_debt_asset, new_debt, amount);
+        assert!(amount_out >= amount, "Slippage exceeded");
+        Self::repay_for_user(&env, &user, &from_debt_asset, amount);
+        
+        let health_factor = Self::calculate_health_factor(env.clone(), user.clone(), None);
+        assert!(health_factor >= MIN_HEALTH_FACTOR, "Insufficient collateral after swap");
+        
+        let surplus = amount_out - amount;
+        if surplus > 0 {
+            Self::transfer_to_user(&env, &from_debt_asset, &user, surplus);
+        }
+        
+        Self::record_health_snapshot(&env, &user);
+        
+        env.events().publish(
+            (symbol_short!("debt_swap"), &user),
+            (from_debt_asset, to_debt_asset, amount, new_debt, health_factor)
+        );
+        
+        new_debt
+    }
+
+    /// Lever up a position: supply, then borrow, swap and re-supply until the target is reached.
+    /// `target_leverage` is total collateral over the initial amount, in basis points (20000 = 2x).
+    pub fn leverage(
+        env: Env,
+        user: Address,
+        collateral_asset: Address,
+        borrow_asset: Address,
+        initial_amount: u128,
+        target_leverage: u128,
+        min_health: u128,
+    ) -> u128 {
+        user.require_auth();
+        
+        assert!(Self::is_asset_supported(&env, &collateral_asset), "Asset not supported");
+        assert!(Self::is_asset_supported(&env, &borrow_asset), "Asset not supported");
+        assert!(collateral_asset != borrow_asset, "Assets must differ");
+        assert!(initial_amount > 0, "Amount must be positive");
+        assert!(target_leverage > 10000 && target_leverage <= MAX_LEVERAGE, "Invalid target leverage");
+        assert!(min_health >= MIN_HEALTH_FACTOR, "Minimum health below liquidation buffer");
+        
+        Self::record_twap_observation(&env, &collateral_asset);
+        Self::record_twap_observation(&env, &borrow_asset);
+        
+        // Supply the initial deposit
+        Self::transfer_from_user(&env, &collateral_asset, &user, &env.current_contract_address(), initial_amount);
+        let (blend_pool, mut btokens_received) = Self::supply_for_user(&env, &user, &collateral_asset, initial_amount);
+        
+        let target_supply = initial_amount * target_leverage / 10000;
+        let mut supplied = initial_amount;
+        let mut borrowed = 0u128;
+        let mut loops = 0u32;
+        while supplied < target_supply && loops < MAX_LEVERAGE_LOOPS {
+            loops += 1;
+            
+            // Each round borrows no more than keeps the position above min_health
+            let values = Self::calculate_position_values(&env, &user, None)
+                .unwrap_or_else(|e| panic_with_error!(&env, e));
+            let max_debt_value = values.borrow_limit * 1_000_000 / min_health;
+            let room = Self::value_to_amount(&env, &borrow_asset, max_debt_value.saturating_sub(values.debt_value))
+                .unwrap_or_else(|e| panic_with_error!(&env, e));
+            let needed = Self::estimate_amount_in(&env, &borrow_asset, &collateral_asset, target_supply - supplied)
+                .unwrap_or_else(|e| panic_with_error!(&env, e));
+            let borrow_amount = needed.min(room);
+            if borrow_amount == 0 {
+                break;
+            }
+            
+            Self::check_borrow_rules(&env, &user, &borrow_asset, borrow_amount);
+            Self::borrow_for_user(&env, &user, &borrow_asset, borrow_amount);
+            
+            let quote = Self::oracle_quote(&env, &borrow_asset, &collateral_asset, borrow_amount)
+                .unwrap_or_else(|e| panic_with_error!(&env, e));
+            let min_out = quote * (10000 - SWAP_ESTIMATE_BUFFER) / 10000;
+            let amount_out = Self::execute_soroswap(&env, &borrow_asset, &collateral_asset, borrow_amount, min_out);
+            assert!(amount_out >= min_out, "Slippage exceeded");
+            
+            let (_, received) = Self::supply_for_user(&env, &user, &collateral_asset, amount_out);
+            btokens_received += received;
+            supplied += amount_out;
+            borrowed += borrow_amount;
+        }
+        
+        let health_factor = Self::calculate_health_factor(env.clone(), user.clone(), None);
+        assert!(health_factor >= min_health, "Health factor below minimum");
+        
+        // Transfer bTokens for all supplied collateral to user
+        Self::transfer_to_user(&env, &blend_pool.reserve_asset, &user, btokens_received);
+        
+        Self::record_health_snapshot(&env, &user);
+        
+        env.events().publish(
+            (symbol_short!("leverage"), &user),
+            (collateral_asset, borrow_asset, initial_amount, supplied, borrowed, health_factor)
+        );
+        
+        supplied
+    }
+
+    /// Unwind a levered position: withdraw collateral, swap it to the debt asset and repay.
+    /// `target_leverage` is collateral over equity for the pair, in basis points (10000 repays all debt).
+    /// bTokens for the withdrawn collateral are taken back from the user.
+    pub fn deleverage(
+        env: Env,
+        user: Address,
+        collateral_asset: Address,
+        debt_asset: Address,
+        target_leverage: u128,
+    ) -> u128 {
+        user.require_auth();
+        
+        assert!(Self::is_asset_supported(&env, &collateral_asset), "Asset not supported");
+        assert!(Self::is_asset_supported(&env, &debt_asset), "Asset not supported");
+        assert!(collateral_asset != debt_asset, "Assets must differ");
+        assert!(target_leverage >= 10000, "Invalid target leverage");
+        
+        Self::record_twap_observation(&env, &collateral_asset);
+        Self::record_twap_observation(&env, &debt_asset);
+        
+        let position = Self::get_user_position(env.clone(), user.clone());
+        let supplied = position.supplied_assets.get(collateral_asset.clone()).unwrap_or(0);
+        let debt = position.borrowed_assets.get(debt_asset.clone()).unwrap_or(0);
+        assert!(debt > 0, "No debt to unwind");
+        
+        let collateral_value = Self::get_asset_value(&env, &collateral_asset, supplied)
+            .unwrap_or_else(|e| panic_with_error!(&env, e));
+        let debt_value = Self::get_asset_value(&env, &debt_asset, debt)
+            .unwrap_or_else(|e| panic_with_error!(&env, e));
+        assert!(collateral_value > debt_value, "Position has no equity");
+        let equity = collateral_value - debt_value;
+        assert!(collateral_value * 10000 / equity > target_leverage, "Already at or below target leverage");
+        
+        // Swapping collateral into debt reduces both sides equally and keeps equity unchanged
+        let reduce_value = (collateral_value - target_leverage * equity / 10000).min(debt_value);
+        let repay_target = Self::value_to_amount(&env, &debt_asset, reduce_value)
+            .unwrap_or_else(|e| panic_with_error!(&env, e))
+            .min(debt);
+        let withdraw_amount = Self::estimate_amount_in(&env, &collateral_asset, &debt_asset, repay_target)
+            .unwrap_or_else(|e| panic_with_error!(&env, e))
+            .min(supplied);
+        
+        let quote = Self::oracle_quote(&env, &collateral_asset, &debt_asset, withdraw_amount)
+            .unwrap_or_else(|e| panic_with_error!(&env, e));
+        let min_out = quote * (10000 - SWAP_ESTIMATE_BUFFER) / 10000;
+        let (btokens_returned, repaid) = Self::sell_collateral_for_debt(
+            &env, &user, &collateral_asset, &debt_asset, withdraw_amount, min_out, debt
+        );
+        
+        let health_factor = Self::calculate_health_factor(env.clone(), user.clone(), None);
+        assert!(health_factor >= MIN_HEALTH_FACTOR, "Insufficient collateral after unwind");
+        
+        Self::record_health_snapshot(&env, &user);
+        
+        env.events().publish(
+            (symbol_short!("delever"), &user),
+            (collateral_asset, debt_asset, withdraw_amount, btokens_returned, repaid, health_factor)
+        );
+        
+        repaid
+    }
+
+    /// Repay debt by selling supplied collateral, for users without a wallet balance of the debt asset.
+    /// bTokens for the sold collateral are taken back from the user.
+    pub fn repay_with_collateral(
+        env: Env,
+        user: Address,
+        collateral_asset: Address,
+        debt_asset: Address,
+        amount: u128,
+        max_collateral_in: u128,
+    ) -> u128 {
+        user.require_auth();
+        
+        assert!(Self::is_asset_supported(&env, &collateral_asset), "Asset not supported");
+        assert!(Self::is_asset_supported(&env, &debt_asset), "Asset not supported");
+        assert!(collateral_asset != debt_asset, "Assets must differ");
+        assert!(amount > 0, "Amount must be positive");
+        
+        let position = Self::get_user_position(env.clone(), user.clone());
+        let debt = position.borrowed_assets.get(debt_asset.clone()).unwrap_or(0);
+        let supplied = position.supplied_assets.get(collateral_asset.clone()).unwrap_or(0);
+        assert!(amount <= debt, "Amount exceeds debt");
+        
+        Self::record_twap_observation(&env, &collateral_asset);
+        Self::record_twap_observation(&env, &debt_asset);
+        
+        let health_before = Self::calculate_health_factor(env.clone(), user.clone(), None);
+        
+        // Size the collateral sale from oracle prices, bounded by the user's limit
+        let collateral_in = Self::estimate_amount_in(&env, &collateral_asset, &debt_asset, amount)
+            .unwrap_or_else(|e| panic_with_error!(&env, e));
+        assert!(collateral_in <= max_collateral_in, "Collateral in exceeds maximum");
+        assert!(collateral_in <= supplied, "Insufficient collateral supplied");
+        
+        let (btokens_returned, _) = Self::sell_collateral_for_debt(
+            &env, &user, &collateral_asset, &debt_asset, collateral_in, amount, amount
+        );
+        
+        // Positions already below the minimum may repay as long as health improves
+        let health_factor = Self::calculate_health_factor(env.clone(), user.clone(), None);
+        assert!(
+            health_factor >= MIN_HEALTH_FACTOR || health_factor > health_before,
+            "Repayment would worsen position"
+        );
+        
+        Self::record_health_snapshot(&env, &user);
+        
+        env.events().publish(
+            (symbol_short!("repay_col"), &user),
+            (collateral_asset, debt_asset, collateral_in, btokens_returned, amount, health_factor)
+        );
+        
+        collateral_in
+    }
+
+    /// Allow a delegatee to borrow up to `amount` of an asset against the delegator's collateral.
+    /// Setting the allowance to 0 revokes further borrowing.
+    pub fn approve_delegation(env: Env, delegator: Address, delegatee: Address, asset: Address, amount: u128) {
+        delegator.require_auth();
+        
+        assert!(Self::is_asset_supported(&env, &asset), "Asset not supported");
+        assert!(delegator != delegatee, "Cannot delegate to self");
+        
+        let key = (symbol_short!("deleg"), delegator.clone(), delegatee.clone(), asset.clone());
+        let out_key = (symbol_short!("dlg_out"), delegator.clone());
+        let in_key = (symbol_short!("dlg_in"), delegatee.clone());
+        let mut granted: Vec<(Address, Address)> = env.storage().persistent().get(&out_key).unwrap_or(Vec::new(&env));
+        let mut received: Vec<(Address, Address)> = env.storage().persistent().get(&in_key).unwrap_or(Vec::new(&env));
+        let out_entry = (delegatee.clone(), asset.clone());
+        let in_entry = (delegator.clone(), asset.clone());
+        
+        let mut delegation = Self::get_delegation(env.clone(), delegator.clone(), delegatee.clone(), asset.clone());
+        delegation.allowance = amount;
+        // Revoked delegations stay on record while they have borrowed against the delegator
+        if amount == 0 && delegation.borrowed == 0 {
+            env.storage().persistent().remove(&key);
+            if let Some(index) = granted.first_index_of(&out_entry) {
+                granted.remove(index);
+            }
+            if let Some(index) = received.first_index_of(&in_entry) {
+                received.remove(index);
+            }
+        } else {
+            env.storage().persistent().set(&key, &delegation);
+            if !granted.contains(&out_entry) {
+                granted.push_back(out_entry);
+            }
+            if !received.contains(&in_entry) {
+                received.push_back(in_entry);
+            }
+        }
+        env.storage().persistent().set(&out_key, &granted);
+        env.storage().persistent().set(&in_key, &received);
+        
+        env.events().publish(
+            (symbol_short!("deleg"), &delegator),
+            (delegatee, asset, amount)
+        );
+    }
+
+    /// Borrow against a delegator's collateral; the debt is recorded on the delegator's position
+    pub fn borrow_delegated(env: Env, delegatee: Address, delegator: Address, asset: Address, amount: u128) {
+        delegatee.require_auth();
+        
+        assert!(Self::is_asset_supported(&env, &asset), "Asset not supported");
+        assert!(amount > 0, "Amount must be positive");
+        
+        let mut delegation = Self::get_delegation(env.clone(), delegator.clone(), delegatee.clone(), asset.clone());
+        assert!(amount <= delegation.allowance, "Amount exceeds delegated allowance");
+        
+        Self::record_twap_observation(&env, &asset);
+        
+        // The delegator's collateral must support the new debt
+        let health_factor = Self::calculate_health_factor(env.clone(), delegator.clone(), Some((asset.clone(), amount)));
+        assert!(health_factor >= MIN_HEALTH_FACTOR, "Insufficient collateral for borrow");
+        
+        Self::borrow_for_user(&env, &delegator, &asset, amount);
+        
+        delegation.allowance -= amount;
+        delegation.borrowed += amount;
+        env.storage().persistent().set(
+            &(symbol_short!("deleg"), delegator.clone(), delegatee.clone(), asset.clone()),
+            &delegation
+        );
+        
+        // Borrowed funds go to the delegatee
+        Self::transfer_to_user(&env, &asset, &delegatee, amount);
+        
+        Self::record_health_snapshot(&env, &delegator);
+        
+        env.events().publish(
+            (symbol_short!("deleg_brw"), &delegatee),
+            (delegator, asset, amount, health_factor)
+        );
+    }
+
+    /// Get a delegation (zero allowance if none was granted)
+    pub fn get_delegation(env: Env, delegator: Address, delegatee: Address, asset: Address) -> CreditDelegation {
+        env.storage()
+            .persistent()
+            .get(&(symbol_short!("deleg"), delegator.clone(), delegatee.clone(), asset.clone()))
+            .unwrap_or(CreditDelegation {
+                delegator,
+                delegatee,
+                asset,
+                allowance: 0,
+                borrowed: 0,
+            })
+    }
+
+    /// Get all delegations granted by a delegator
+    pub fn get_delegations_by_delegator(env: Env, delegator: Address) -> Vec<CreditDelegation> {
+        let entries: Vec<(Address, Address)> = env.storage()
+            .persistent()
+            .get(&(symbol_short!("dlg_out"), delegator.clone()))
+            .unwrap_or(Vec::new(&env));
+        
+        let mut delegations = Vec::new(&env);
+        for (delegatee, asset) in entries.iter() {
+            delegations.push_back(Self::get_delegation(env.clone(), delegator.clone(), delegatee, asset));
+        }
+        delegations
+    }
+
+    /// Get all delegations granted to a delegatee
+    pub fn get_delegations_to(env: Env, delegatee: Address) -> Vec<CreditDelegation> {
+        let entries: Vec<(Address, Address)> = env.storage()
+            .persistent()
+            .get(&(symbol_short!("dlg_in"), delegatee.clone()))
+            .unwrap_or(Vec::new(&env));
+        
+        let mut delegations = Vec::new(&env);
+        for (delegator, asset) in entries.iter() {
+            delegations.push_back(Self::get_delegation(env.clone(), delegator, delegatee.clone(), asset));
+        }
+        delegations
+    }
+
+    /// Lend hub-held liquidity to a receiver contract for the duration of one invocation.
+    /// The receiver's `exec_op(asset, amount, fee, params)` must approve the hub to pull amount + fee.
+    pub fn flash_loan(
+        env: Env,
+        receiver: Address,
+        asset: Address,
+        amount: u128,
+        params: Bytes,
+    ) -> u128 {
+        assert!(Self::is_asset_supported(&env, &asset), "Asset not supported");
+        assert!(amount > 0, "Amount must be positive");
+        
+        // Nested flash loans could lend out funds already on loan
+        let lock_key = symbol_short!("fl_lock");
+        assert!(!env.storage().instance().has(&lock_key), "Flash loan already in progress");
+        env.storage().instance().set(&lock_key, &true);
+        
+        // Any hub-held balance can be lent; repayment is pulled before the invocation ends
+        let hub = env.current_contract_address();
+        if Self::token_balance(&env, &asset, &hub) < amount {
+            panic_with_error!(&env, HubError::InsufficientLiquidity);
+        }
+        let fee = amount * Self::get_flash_loan_fee(env.clone()) / 10000;
+        
+        // Send funds and hand control to the receiver
+        Self::transfer_to_user(&env, &asset, &receiver, amount);
+        let accepted = env.invoke_contract::<bool>(
+            &receiver,
+            &symbol_short!("exec_op"),
+            soroban_sdk::vec![
+                &env,
+                asset.into_val(&env),
+                amount.into_val(&env),
+                fee.into_val(&env),
+                params.into_val(&env),
+            ],
+        );
+        assert!(accepted, "Flash loan rejected by receiver");
+        
+        // Pull loan plus fee from the receiver; other deposits made during the callback do not count
+        let repaid = env.try_invoke_contract::<(), soroban_sdk::xdr::Error>(
+            &asset,
+            &symbol_short!("xferfrom"),
+            soroban_sdk::vec![
+                &env,
+                receiver.into_val(&env),
+                hub.into_val(&env),
+                (amount + fee).into_val(&env),
+            ],
+        );
+        if !matches!(repaid, Ok(Ok(()))) {
+            panic_with_error!(&env, HubError::FlashLoanNotRepaid);
+        }
+        
+        Self::add_to_reward_pool(&env, &asset, fee);
+        env.storage().instance().remove(&lock_key);
+        
+        env.events().publish(
+            (symbol_short!("flash"), &receiver),
+            (asset, amount, fee)
+        );
+        
+        fee
+    }
+
+    /// Admin function to set the flash loan fee
+    pub fn set_flash_loan_fee(env: Env, admin: Address, fee: u128) {
+        admin.require_auth();
+        
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can set flash loan fee");
+        assert!(fee <= MAX_FLASH_LOAN_FEE, "Flash loan fee exceeds maximum");
+        
+        env.storage().instance().set(&symbol_short!("fl_fee"), &fee);
+        
+        env.events().publish(
+            (symbol_short!("fl_fee"), &admin),
+            fee
+        );
+    }
+
+    /// Get the flash loan fee in basis points
+    pub fn get_flash_loan_fee(env: Env) -> u128 {
+        env.storage()
+            .instance()
+            .get(&symbol_short!("fl_fee"))
+            .unwrap_or(FLASH_LOAN_FEE)
+    }
+
+    /// Stake bTokens to earn protocol fees
+    pub fn stake_btokens(
+        env: Env,
+        user: Address,
+        btoken: Address,
+        amount: u128,
+    ) {
+        user.require_auth();
+        
+        // Transfer bTokens from user
+        Self::transfer_from_user(&env, &btoken, &user, &env.current_contract_address(), amount);
+        
+        // Update rewards, staking position and pool
+        Self::stake_for_user(&env, &user, &btoken, amount);
+        
+        env.events().publish(
+            (symbol_short!("stake"), &user),
+            (btoken, amount)
+        );
+    }
+
+    /// Swap into a supported asset and supply it to Blend in one invocation
+    pub fn zap_supply(
+        env: Env,
+        user: Address,
+        token_in: Address,
+        target_asset: Address,
+        amount: u128,
+        min_out: u128,
+    ) -> u128 {
+        user.require_auth();
+        
+        let (blend_pool, supplied, btokens_received) =
+            Self::zap_into_blend(&env, &user, &token_in, &target_asset, amount, min_out);
+        
+        // Transfer bTokens to user
+        Self::transfer_to_user(&env, &blend_pool.reserve_asset, &user, btokens_received);
+        
+        env.events().publish(
+            (symbol_short!("zap_sup"), &user),
+            (token_in, target_asset, amount, supplied, btokens_received)
+        );
+        
+        supplied
+    }
+
+    /// Swap into a supported asset, supply it to Blend and stake the bTokens in one invocation
+    pub fn zap_stake(
+        env: Env,
+        user: Address,
+        token_in: Address,
+        target_asset: Address,
+        amount: u128,
+        min_out: u128,
+    ) -> u128 {
+        user.require_auth();
+        
+        let (blend_pool, supplied, btokens_received) =
+            Self::zap_into_blend(&env, &user, &token_in, &target_asset, amount, min_out);
+        
+        // bTokens stay in the hub as the user's stake
+        Self::stake_for_user(&env, &user, &blend_pool.reserve_asset, btokens_received);
+        
+        env.events().publish(
+            (symbol_short!("zap_stk"), &user),
+            (token_in, target_asset, amount, supplied, btokens_received)
+        );
+        
+        btokens_received
+    }
+
+    /// Unstake bTokens and claim rewards
+    pub fn unstake_and_claim(
+        env: Env,
+        user: Address,
+        btoken: Address,
+        amount: u128,
+    ) -> u128 {
+        user.require_auth();
+        
+        // Update user rewards before unstaking
+        Self::update_user_rewards(&env, &user, &btoken);
+        
+        // Calculate claimable rewards
+        let rewards = Self::get_claimable_rewards(&env, &user);
+        
+        // Update staking position
+        Self::update_staking_position(&env, &user, &btoken, amount, false);
+        
+        // Update staking pool
+        Self::update_staking_pool(&env, &btoken, amount, false);
+        
+        // Transfer bTokens back to user
+        Self::transfer_to_user(&env, &btoken, &user, amount);
+        
+        // Transfer rewards if any (in collected fee tokens)
+        if rewards > 0 {
+            Self::distribute_rewards(&env, &user, rewards);
+        }
+        
+        env.events().publish(
+            (symbol_short!("unstake"), &user),
+            (btoken, amount, rewards)
+        );
+        
+        rewards
+    }
+
+    /// Get user's position across all protocols
+    pub fn get_user_position(env: Env, user: Address) -> UserPosition {
+        env.storage()
+            .persistent()
+            .get(&(symbol_short!("pos"), user))
+            .unwrap_or(UserPosition {
+                supplied_assets: Map::new(&env),
+                borrowed_assets: Map::new(&env),
+                staked_lp_tokens: Map::new(&env),
+                rewards_earned: 0,
+                last_reward_update: env.ledger().timestamp(),
+                emode_category: 0,
+                collateral_enabled: Map::new(&env),
+                supplied_btokens: Map::new(&env),
+                isolation_asset: None,
+                isolated_debt: 0,
+            })
+    }
+
+    /// Calculate user's health factor for borrowing
     pub fn calculate_health_factor(
         env: Env,
         user: Address,
         additional_borrow: Option<(Address, u128)>
     ) -> u128 {
-        match Self::calculate_health_factor_internal(env, user, additional_borrow) {
+        match Self::calculate_health_factor_internal(env.clone(), user, additional_borrow) {
             Ok(val) => val,
-            Err(HubError::AssetNotSupported) => panic!("Asset not supported"),
-            Err(_) => panic!("Health factor calculation failed"),
+            Err(e) => panic_with_error!(&env, e),
         }
     }
 
@@ -365,45 +1875,324 @@ impl StellarDeFiHub {
         user: Address,
         additional_borrow: Option<(Address, u128)>
     ) -> Result<u128, HubError> {
+        let values = Self::calculate_position_values(&env, &user, additional_borrow)?;
+        if values.debt_value == 0 {
+            return Ok(u128::MAX); // No debt = infinite health
+        }
+        // Health factor = LTV-weighted collateral / debt (in 6 decimals)
+        Ok((values.borrow_limit * 1_000_000) / values.debt_value)
+    }
+
+    fn calculate_position_values(
+        env: &Env,
+        user: &Address,
+        additional_borrow: Option<(Address, u128)>
+    ) -> Result<BorrowCapacity, HubError> {
         let position = Self::get_user_position(env.clone(), user.clone());
-        let mut total_collateral_value = 0u128;
-        let mut total_debt_value = 0u128;
+        let emode = Self::get_active_emode(env, &position, &additional_borrow)?;
+        let mut collateral_value = 0u128;
+        let mut borrow_limit = 0u128;
+        let mut liquidation_limit = 0u128;
+        let mut debt_value = 0u128;
+        let mut collateral_count = 0u32;
+        let mut isolated_asset: Option<AssetConfig> = None;
         // Calculate collateral value (supplied assets)
+        // Prices fail closed: an asset without an accepted price aborts the calculation
         for (asset, amount) in position.supplied_assets.iter() {
-            if let Some(price) = Self::get_asset_price_safe(&env, &asset) {
-                let asset_config = Self::get_asset_config(&env, &asset)?;
-                if asset_config.is_collateral {
-                    let collateral_value = (amount * price * asset_config.collateral_factor) /
-                                         (Self::get_price_precision(&env, &asset)? * 10000);
-                    total_collateral_value += collateral_value;
+            let asset_config = Self::get_asset_config(env, &asset)?;
+            let enabled = position.collateral_enabled.get(asset.clone()).unwrap_or(true);
+            if asset_config.is_collateral && enabled && amount > 0 {
+                collateral_count += 1;
+                if asset_config.is_isolated {
+                    isolated_asset = Some(asset_config.clone());
                 }
+                let (borrow_ltv, liquidation_threshold) = match &emode {
+                    Some(category) if asset_config.emode_category == category.id => (
+                        category.borrow_ltv.max(asset_config.borrow_ltv),
+                        category.liquidation_threshold.max(asset_config.liquidation_threshold),
+                    ),
+                    _ => (asset_config.borrow_ltv, asset_config.liquidation_threshold),
+                };
+                let value = Self::get_asset_value(env, &asset, amount)?;
+                collateral_value += value;
+                borrow_limit += value * borrow_ltv / 10000;
+                liquidation_limit += value * liquidation_threshold / 10000;
             }
         }
         // Calculate debt value (borrowed assets + potential new borrow)
         for (asset, amount) in position.borrowed_assets.iter() {
-            if let Some(price) = Self::get_asset_price_safe(&env, &asset) {
-                let debt_value = (amount * price) / Self::get_price_precision(&env, &asset)?;
-                total_debt_value += debt_value;
-            }
+            debt_value += Self::get_asset_value(env, &asset, amount)?;
         }
         // Add additional borrow if provided
-        if let Some((borrow_asset, borrow_amount)) = additional_borrow {
-            if let Some(price) = Self::get_asset_price_safe(&env, &borrow_asset) {
-                let additional_debt = (borrow_amount * price) / Self::get_price_precision(&env, &borrow_asset)?;
-                total_debt_value += additional_debt;
+        if let Some((borrow_asset, borrow_amount)) = &additional_borrow {
+            debt_value += Self::get_asset_value(env, borrow_asset, *borrow_amount)?;
+        }
+        // Isolated collateral: used alone, only against stablecoins, up to the debt ceiling
+        if let Some(isolated) = isolated_asset {
+            if debt_value > 0 && collateral_count > 1 {
+                return Err(HubError::IsolationViolation);
+            }
+            for (asset, _) in position.borrowed_assets.iter() {
+                if !Self::get_asset_config(env, &asset)?.is_stablecoin {
+                    return Err(HubError::IsolationViolation);
+                }
+            }
+            if let Some((borrow_asset, borrow_amount)) = &additional_borrow {
+                if !Self::get_asset_config(env, borrow_asset)?.is_stablecoin {
+                    return Err(HubError::IsolationViolation);
+                }
+                let isolated_debt = Self::get_isolated_debt(env.clone(), isolated.address.clone());
+                if isolated.debt_ceiling > 0 && isolated_debt + borrow_amount > isolated.debt_ceiling {
+                    return Err(HubError::DebtCeilingExceeded);
+                }
             }
         }
-        if total_debt_value == 0 {
-            return Ok(u128::MAX); // No debt = infinite health
+        Ok(BorrowCapacity {
+            collateral_value,
+            borrow_limit,
+            liquidation_limit,
+            debt_value,
+            available: borrow_limit.saturating_sub(debt_value),
+        })
+    }
+
+    /// Get how much more a user can borrow before hitting their LTV limit
+    pub fn get_borrow_capacity(env: Env, user: Address) -> BorrowCapacity {
+        Self::calculate_position_values(&env, &user, None)
+            .unwrap_or_else(|e| panic_with_error!(&env, e))
+    }
+
+    /// Get user's health factor against liquidation thresholds (below 1_000_000 is liquidatable)
+    pub fn get_liquidation_health(env: Env, user: Address) -> u128 {
+        let values = Self::calculate_position_values(&env, &user, None)
+            .unwrap_or_else(|e| panic_with_error!(&env, e));
+        if values.debt_value == 0 {
+            return u128::MAX;
+        }
+        (values.liquidation_limit * 1_000_000) / values.debt_value
+    }
+
+    /// Get hub-wide supply and borrow totals for an asset
+    pub fn get_asset_totals(env: Env, asset: Address) -> AssetTotals {
+        env.storage()
+            .persistent()
+            .get(&(symbol_short!("totals"), asset))
+            .unwrap_or(AssetTotals {
+                total_supplied: 0,
+                total_borrowed: 0,
+            })
+    }
+
+    /// Admin function to set hub-wide supply and borrow caps for an asset (0 = unlimited)
+    pub fn set_asset_caps(env: Env, admin: Address, asset: Address, supply_cap: u128, borrow_cap: u128) {
+        admin.require_auth();
+        
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can set asset caps");
+        
+        let mut config = Self::get_asset_config(&env, &asset).unwrap_or_else(|e| panic_with_error!(&env, e));
+        config.supply_cap = supply_cap;
+        config.borrow_cap = borrow_cap;
+        Self::save_asset_config(&env, &config);
+        
+        env.events().publish(
+            (symbol_short!("caps_upd"), &admin),
+            (asset, supply_cap, borrow_cap)
+        );
+    }
+
+    /// Admin function to set an asset's borrow LTV, liquidation threshold and liquidation bonus
+    pub fn set_asset_risk_params(
+        env: Env,
+        admin: Address,
+        asset: Address,
+        borrow_ltv: u128,
+        liquidation_threshold: u128,
+        liquidation_bonus: u128
+    ) {
+        admin.require_auth();
+
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can set risk parameters");
+        assert!(borrow_ltv < liquidation_threshold || borrow_ltv == 0, "Borrow LTV must sit below liquidation threshold");
+        assert!(liquidation_threshold <= 10000, "Invalid liquidation threshold");
+        // Seized collateral plus bonus must still fit inside the threshold
+        assert!(
+            liquidation_threshold * (10000 + liquidation_bonus) / 10000 <= 10000,
+            "Liquidation bonus too high"
+        );
+
+        let mut config = Self::get_asset_config(&env, &asset).unwrap_or_else(|e| panic_with_error!(&env, e));
+        config.borrow_ltv = borrow_ltv;
+        config.liquidation_threshold = liquidation_threshold;
+        config.liquidation_bonus = liquidation_bonus;
+        config.is_collateral = borrow_ltv > 0;
+        Self::save_asset_config(&env, &config);
+
+        env.events().publish(
+            (symbol_short!("risk_upd"), &admin),
+            (asset, borrow_ltv, liquidation_threshold, liquidation_bonus)
+        );
+    }
+
+    /// Get total debt borrowed hub-wide against an isolated collateral asset
+    pub fn get_isolated_debt(env: Env, asset: Address) -> u128 {
+        env.storage()
+            .persistent()
+            .get(&(symbol_short!("iso_debt"), asset))
+            .unwrap_or(0)
+    }
+
+    /// Admin function to put an asset in or out of isolation mode
+    pub fn set_asset_isolation(env: Env, admin: Address, asset: Address, is_isolated: bool, debt_ceiling: u128) {
+        admin.require_auth();
+        
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can update asset isolation");
+        
+        let mut config = Self::get_asset_config(&env, &asset).unwrap_or_else(|e| panic_with_error!(&env, e));
+        config.is_isolated = is_isolated;
+        config.debt_ceiling = debt_ceiling;
+        Self::save_asset_config(&env, &config);
+        
+        env.events().publish(
+            (symbol_short!("iso_upd"), &admin),
+            (asset, is_isolated, debt_ceiling)
+        );
+    }
+
+    /// Admin function to mark an asset as a stablecoin (borrowable in isolation mode)
+    pub fn set_asset_stablecoin(env: Env, admin: Address, asset: Address, is_stablecoin: bool) {
+        admin.require_auth();
+        
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can update stablecoin flags");
+        
+        let mut config = Self::get_asset_config(&env, &asset).unwrap_or_else(|e| panic_with_error!(&env, e));
+        config.is_stablecoin = is_stablecoin;
+        Self::save_asset_config(&env, &config);
+        
+        env.events().publish(
+            (symbol_short!("stbl_upd"), &admin),
+            (asset, is_stablecoin)
+        );
+    }
+
+    /// Opt in to (or out of, with category 0) an efficiency mode category
+    pub fn set_emode(env: Env, user: Address, category: u32) {
+        user.require_auth();
+        
+        let mut position = Self::get_user_position(env.clone(), user.clone());
+        if category != 0 {
+            let emode = Self::get_emode_category(env.clone(), category);
+            // Existing debt must already be in the category
+            for (asset, _) in position.borrowed_assets.iter() {
+                let asset_config = Self::get_asset_config(&env, &asset).unwrap_or_else(|e| panic_with_error!(&env, e));
+                if asset_config.emode_category != emode.id {
+                    panic_with_error!(&env, HubError::EModeViolation);
+                }
+            }
+        }
+        position.emode_category = category;
+        Self::save_user_position(&env, &user, &position);
+        
+        // Leaving a category can lower collateral value
+        let health_factor = Self::calculate_health_factor(env.clone(), user.clone(), None);
+        assert!(health_factor >= MIN_HEALTH_FACTOR, "Insufficient collateral for emode change");
+        
+        env.events().publish(
+            (symbol_short!("emode"), &user),
+            (category, health_factor)
+        );
+    }
+
+    /// Choose whether a supplied asset counts towards the user's collateral
+    pub fn set_use_as_collateral(env: Env, user: Address, asset: Address, enabled: bool) {
+        user.require_auth();
+        
+        let asset_config = Self::get_asset_config(&env, &asset).unwrap_or_else(|e| panic_with_error!(&env, e));
+        assert!(!enabled || asset_config.is_collateral, "Asset cannot be used as collateral");
+        
+        let mut position = Self::get_user_position(env.clone(), user.clone());
+        if enabled {
+            position.collateral_enabled.remove(asset.clone());
+        } else {
+            position.collateral_enabled.set(asset.clone(), false);
+        }
+        Self::save_user_position(&env, &user, &position);
+        
+        // Removing collateral must leave existing debt covered
+        let health_factor = Self::calculate_health_factor(env.clone(), user.clone(), None);
+        if !enabled {
+            assert!(health_factor >= MIN_HEALTH_FACTOR, "Insufficient collateral to disable asset");
+        }
+        Self::record_health_snapshot(&env, &user);
+        
+        env.events().publish(
+            (symbol_short!("coll_use"), &user),
+            (asset, enabled, health_factor)
+        );
+    }
+
+    /// Check whether a supplied asset counts towards the user's collateral
+    pub fn is_using_as_collateral(env: Env, user: Address, asset: Address) -> bool {
+        let position = Self::get_user_position(env.clone(), user);
+        let asset_config = Self::get_asset_config(&env, &asset).unwrap_or_else(|e| panic_with_error!(&env, e));
+        asset_config.is_collateral && position.collateral_enabled.get(asset).unwrap_or(true)
+    }
+
+    /// Get an efficiency mode category
+    pub fn get_emode_category(env: Env, category: u32) -> EModeCategory {
+        env.storage()
+            .instance()
+            .get(&(symbol_short!("emode"), category))
+            .unwrap_or_else(|| panic!("Emode category not found"))
+    }
+
+    /// Admin function to create or update an efficiency mode category
+    pub fn set_emode_category(env: Env, admin: Address, category: EModeCategory) {
+        admin.require_auth();
+        
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can configure emode categories");
+        assert!(category.id != 0, "Category 0 is reserved");
+        assert!(
+            category.borrow_ltv <= category.liquidation_threshold && category.liquidation_threshold <= 10000,
+            "Invalid emode parameters"
+        );
+        
+        env.storage().instance().set(&(symbol_short!("emode"), category.id), &category);
+        
+        env.events().publish(
+            (symbol_short!("emode_cfg"), &admin),
+            (category.id, category.borrow_ltv, category.liquidation_threshold)
+        );
+    }
+
+    /// Admin function to assign an asset to an efficiency mode category
+    pub fn set_asset_emode_category(env: Env, admin: Address, asset: Address, category: u32) {
+        admin.require_auth();
+        
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can configure emode categories");
+        if category != 0 {
+            Self::get_emode_category(env.clone(), category);
         }
-        // Health factor = collateral_value / debt_value (in 6 decimals)
-        Ok((total_collateral_value * 1_000_000) / total_debt_value)
+        
+        let mut config = Self::get_asset_config(&env, &asset).unwrap_or_else(|e| panic_with_error!(&env, e));
+        config.emode_category = category;
+        Self::save_asset_config(&env, &config);
+        
+        env.events().publish(
+            (symbol_short!("emode_ast"), &admin),
+            (asset, category)
+        );
     }
 
     /// Get asset price with DIA oracle
     pub fn get_asset_price(env: Env, asset: Address) -> u128 {
         Self::get_asset_price_safe(&env, &asset)
-            .unwrap_or_else(|| panic!("Price unavailable"))
+            .unwrap_or_else(|e| panic_with_error!(&env, e))
     }
 
     /// Get supported assets list
@@ -444,22 +2233,213 @@ impl StellarDeFiHub {
         
         env.storage().instance().set(&symbol_short!("rwd_rate"), &new_rate);
         
-        env.events().publish(
-            (symbol_short!("rate_upd"), &admin),
-            new_rate
-        );
+        env.events().publish(
+            (symbol_short!("rate_upd"), &admin),
+            new_rate
+        );
+    }
+
+    /// Admin function to set the fee tier for an asset pair
+    pub fn set_fee_tier(env: Env, admin: Address, token_a: Address, token_b: Address, fee: u128) {
+        admin.require_auth();
+        
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can set fee tiers");
+        assert!(Self::is_asset_supported(&env, &token_a), "Token A not supported");
+        assert!(Self::is_asset_supported(&env, &token_b), "Token B not supported");
+        assert!(fee <= MAX_PROTOCOL_FEE, "Fee exceeds maximum");
+        
+        env.storage().persistent().set(&Self::fee_tier_key(&token_a, &token_b), &fee);
+        
+        env.events().publish(
+            (symbol_short!("fee_tier"), &admin),
+            (token_a, token_b, fee)
+        );
+    }
+
+    /// Admin function to remove a pair fee tier (falls back to the default fee)
+    pub fn remove_fee_tier(env: Env, admin: Address, token_a: Address, token_b: Address) {
+        admin.require_auth();
+        
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can remove fee tiers");
+        
+        env.storage().persistent().remove(&Self::fee_tier_key(&token_a, &token_b));
+        
+        env.events().publish(
+            (symbol_short!("fee_rm"), &admin),
+            (token_a, token_b)
+        );
+    }
+
+    /// Admin function to update the default fee and discount schedule
+    pub fn set_fee_config(env: Env, admin: Address, config: FeeConfig) {
+        admin.require_auth();
+        
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can update fee config");
+        assert!(config.default_fee <= MAX_PROTOCOL_FEE, "Fee exceeds maximum");
+        assert!(config.stake_discount <= 10000, "Invalid staking discount");
+        
+        let mut last_volume = 0u128;
+        for (i, step) in config.volume_discounts.iter().enumerate() {
+            assert!(step.discount <= 10000, "Invalid volume discount");
+            assert!(i == 0 || step.min_volume > last_volume, "Volume discounts must be ascending");
+            last_volume = step.min_volume;
+        }
+        
+        env.storage().instance().set(&symbol_short!("fee_cfg"), &config);
+        
+        env.events().publish(
+            (symbol_short!("fee_cfg"), &admin),
+            config.default_fee
+        );
+    }
+
+    /// Get the protocol fee configuration
+    pub fn get_fee_config(env: Env) -> FeeConfig {
+        env.storage()
+            .instance()
+            .get(&symbol_short!("fee_cfg"))
+            .unwrap_or(FeeConfig {
+                default_fee: PROTOCOL_FEE,
+                volume_discounts: Vec::new(&env),
+                stake_thresholds: Map::new(&env),
+                stake_discount: 0,
+            })
+    }
+
+    /// Get the fee (in basis points) a user pays to swap token_a for token_b
+    pub fn get_effective_fee(env: Env, user: Address, token_a: Address, token_b: Address) -> u128 {
+        let config = Self::get_fee_config(env.clone());
+        let mut fee = env.storage()
+            .persistent()
+            .get(&Self::fee_tier_key(&token_a, &token_b))
+            .unwrap_or(config.default_fee);
+        
+        // Volume discount: highest step the user's 30-day volume qualifies for
+        let volume = Self::get_user_volume(env.clone(), user.clone());
+        let mut volume_discount = 0u128;
+        for step in config.volume_discounts.iter() {
+            if volume >= step.min_volume {
+                volume_discount = step.discount;
+            }
+        }
+        fee = fee * (10000 - volume_discount) / 10000;
+        
+        // Staking discount: any pool's stake meeting that bToken's own threshold,
+        // since bTokens of different pools differ in decimals and value
+        if config.stake_discount > 0 {
+            let position = Self::get_user_position(env.clone(), user);
+            let mut qualifies = false;
+            for (btoken, amount) in position.staked_lp_tokens.iter() {
+                if let Some(threshold) = config.stake_thresholds.get(btoken) {
+                    if amount > 0 && amount >= threshold {
+                        qualifies = true;
+                        break;
+                    }
+                }
+            }
+            if qualifies {
+                fee = fee * (10000 - config.stake_discount) / 10000;
+            }
+        }
+        
+        fee
+    }
+
+    /// Admin function to set the share of swap fees paid to referrers
+    pub fn set_referral_share(env: Env, admin: Address, share: u128) {
+        admin.require_auth();
+        
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can set referral share");
+        assert!(share <= MAX_REFERRAL_SHARE, "Referral share exceeds maximum");
+        
+        env.storage().instance().set(&symbol_short!("ref_share"), &share);
+        
+        env.events().publish(
+            (symbol_short!("ref_share"), &admin),
+            share
+        );
+    }
+
+    /// Get the share of swap fees (in basis points) paid to referrers
+    pub fn get_referral_share(env: Env) -> u128 {
+        env.storage()
+            .instance()
+            .get(&symbol_short!("ref_share"))
+            .unwrap_or(DEFAULT_REFERRAL_SHARE)
+    }
+
+    /// Get a referrer's accrued and lifetime referral fees
+    pub fn get_referral_account(env: Env, referrer: Address) -> ReferralAccount {
+        env.storage()
+            .persistent()
+            .get(&(symbol_short!("referral"), referrer))
+            .unwrap_or(ReferralAccount {
+                accrued: Map::new(&env),
+                total_earned: Map::new(&env),
+                referred_swaps: 0,
+            })
+    }
+
+    /// Claim accrued referral fees for a token
+    pub fn claim_referral_fees(env: Env, referrer: Address, token: Address) -> u128 {
+        referrer.require_auth();
+        
+        let mut account = Self::get_referral_account(env.clone(), referrer.clone());
+        let amount = account.accrued.get(token.clone()).unwrap_or(0);
+        assert!(amount > 0, "No referral fees to claim");
+        
+        account.accrued.remove(token.clone());
+        env.storage().persistent().set(&(symbol_short!("referral"), referrer.clone()), &account);
+        
+        Self::transfer_to_user(&env, &token, &referrer, amount);
+        
+        env.events().publish(
+            (symbol_short!("ref_claim"), &referrer),
+            (token, amount)
+        );
+        
+        amount
+    }
+
+    /// Get user's rolling 30-day swap volume
+    pub fn get_user_volume(env: Env, user: Address) -> u128 {
+        let buckets: Map<u64, u128> = env.storage()
+            .persistent()
+            .get(&(symbol_short!("volume"), user))
+            .unwrap_or(Map::new(&env));
+        let today = env.ledger().timestamp() / SECONDS_PER_DAY;
+        
+        let mut total = 0u128;
+        for (day, amount) in buckets.iter() {
+            if day + VOLUME_WINDOW_DAYS > today {
+                total = total.saturating_add(amount);
+            }
+        }
+        total
     }
 
 
     fn initialize_assets(env: &Env) {
-        for (i, (addr_str, symbol, decimals, collateral_factor, dia_symbol)) in SUPPORTED_ASSETS.iter().enumerate() {
+        for (i, (addr_str, symbol, decimals, borrow_ltv, dia_symbol)) in SUPPORTED_ASSETS.iter().enumerate() {
             let config = AssetConfig {
                 address: Address::from_string(&String::from_str(env, addr_str)),
                 symbol: String::from_str(env, symbol),
                 decimals: *decimals,
-                collateral_factor: *collateral_factor,
-                is_collateral: *collateral_factor > 0,
+                borrow_ltv: *borrow_ltv,
+                liquidation_threshold: (*borrow_ltv + LIQUIDATION_THRESHOLD_BUFFER).min(10000),
+                liquidation_bonus: LIQUIDATION_BONUS,
+                is_collateral: *borrow_ltv > 0,
                 dia_symbol: String::from_str(env, dia_symbol),
+                is_isolated: ISOLATED_ASSETS.contains(symbol),
+                debt_ceiling: if ISOLATED_ASSETS.contains(symbol) { ISOLATION_DEBT_CEILING } else { 0 },
+                is_stablecoin: STABLECOIN_ASSETS.contains(symbol),
+                emode_category: if STABLECOIN_ASSETS.contains(symbol) { STABLECOIN_EMODE } else { 0 },
+                supply_cap: 0,
+                borrow_cap: 0,
             };
             
             let key = (symbol_short!("asset"), i as u32);
@@ -467,12 +2447,66 @@ impl StellarDeFiHub {
         }
     }
 
+    fn initialize_emode(env: &Env) {
+        let stablecoins = EModeCategory {
+            id: STABLECOIN_EMODE,
+            label: String::from_str(env, "Stablecoins"),
+            borrow_ltv: 9300,
+            liquidation_threshold: 9500,
+        };
+        env.storage().instance().set(&(symbol_short!("emode"), STABLECOIN_EMODE), &stablecoins);
+    }
+
+    fn initialize_fees(env: &Env) {
+        let config = FeeConfig {
+            default_fee: PROTOCOL_FEE,
+            volume_discounts: Vec::new(env),
+            stake_thresholds: Map::new(env),
+            stake_discount: 0,
+        };
+        env.storage().instance().set(&symbol_short!("fee_cfg"), &config);
+        env.storage().instance().set(&symbol_short!("ref_share"), &DEFAULT_REFERRAL_SHARE);
+    }
+
     fn initialize_rewards(env: &Env) {
         // Initialize global reward tracking
         env.storage().instance().set(&symbol_short!("rwd_rate"), &1000u128); // 1000 tokens per day base rate
         env.storage().instance().set(&symbol_short!("rwd_start"), &env.ledger().timestamp());
     }
 
+    fn execute_swap_with_fees(
+        env: &Env,
+        user: &Address,
+        params: &SwapParams,
+        referrer: &Option<Address>,
+        kind: Symbol,
+    ) -> (u128, u128) {
+        // Calculate protocol fee (pair tier with volume and staking discounts)
+        let fee = Self::get_effective_fee(env.clone(), user.clone(), params.token_a.clone(), params.token_b.clone());
+        let fee_amount = (params.amount_in * fee) / 10000;
+        let swap_amount = params.amount_in - fee_amount;
+        
+        let amount_out = Self::execute_soroswap(env, &params.token_a, &params.token_b, swap_amount, params.min_amount_out);
+        
+        // Split fee between referrer and reward pool
+        let referral_amount = match referrer {
+            Some(referrer) if referrer != user => Self::accrue_referral_fee(env, referrer, &params.token_a, fee_amount),
+            _ => 0,
+        };
+        Self::add_to_reward_pool(env, &params.token_a, fee_amount - referral_amount);
+        
+        // Track rolling volume for fee discounts
+        Self::record_swap_volume(env, user, &params.token_a, params.amount_in);
+        
+        // Emit swap event tagged with the swap kind (market, limit, dca)
+        env.events().publish(
+            (symbol_short!("swap"), user, kind),
+            (params.token_a.clone(), params.token_b.clone(), params.amount_in, amount_out, fee_amount)
+        );
+        
+        (amount_out, fee_amount)
+    }
+
     fn execute_soroswap(
         env: &Env,
         token_a: &Address,
@@ -568,46 +2602,387 @@ impl StellarDeFiHub {
         );
     }
 
-    fn get_asset_price_safe(env: &Env, asset: &Address) -> Option<u128> {
-        let mut price_sources = Vec::new(env);
+    fn withdraw_from_blend_pool(
+        env: &Env,
+        blend_pool: &BlendPool,
+        asset: &Address,
+        amount: u128,
+    ) {
+        env.invoke_contract::<()>(
+            &blend_pool.pool_id,
+            &symbol_short!("withdraw"),
+            soroban_sdk::vec![
+                env,
+                asset.into_val(env),
+                amount.into_val(env),
+            ],
+        );
+    }
 
-        if let Some(oracle_price) = Self::try_dia_oracle(env, asset) {
-            price_sources.push_back(PriceSource {
-                source_type: String::from_str(env, "oracle"),
-                price: oracle_price,
-                timestamp: env.ledger().timestamp(),
-                confidence: 90,
-            });
+    fn repay_blend_pool(
+        env: &Env,
+        blend_pool: &BlendPool,
+        asset: &Address,
+        amount: u128,
+    ) {
+        env.invoke_contract::<()>(
+            &blend_pool.pool_id,
+            &symbol_short!("repay"),
+            soroban_sdk::vec![
+                env,
+                asset.into_val(env),
+                amount.into_val(env),
+            ],
+        );
+    }
+
+    /// Withdraw part of a user's supply from Blend into the hub, reclaiming the matching bTokens
+    fn withdraw_for_user(env: &Env, user: &Address, asset: &Address, amount: u128) -> u128 {
+        Self::assert_collateral_prices_live(env, user, HubError::WithdrawPaused);
+        let blend_pool = Self::get_or_create_blend_pool(env, asset);
+        let mut position = Self::get_user_position(env.clone(), user.clone());
+        let supplied = position.supplied_assets.get(asset.clone()).unwrap_or(0);
+        assert!(amount <= supplied, "Insufficient supplied balance");
+        
+        // bTokens come back pro rata to the share of the supply being withdrawn
+        let issued = position.supplied_btokens.get(asset.clone()).unwrap_or(0);
+        let btokens = if amount == supplied { issued } else { issued * amount / supplied };
+        if btokens > 0 {
+            Self::transfer_from_user(env, &blend_pool.reserve_asset, user, &env.current_contract_address(), btokens);
+            if btokens == issued {
+                position.supplied_btokens.remove(asset.clone());
+            } else {
+                position.supplied_btokens.set(asset.clone(), issued - btokens);
+            }
+            Self::save_user_position(env, user, &position);
         }
+        
+        // Redeeming burns the reclaimed bTokens, not the hub's own pool position
+        Self::withdraw_from_blend_pool(env, &blend_pool, asset, amount);
+        Self::reduce_user_supply_position(env, user, asset, amount);
+        btokens
+    }
 
-        if let Some(dex_price) = Self::get_dex_price(env, asset) {
-            price_sources.push_back(PriceSource {
-                source_type: String::from_str(env, "dex"),
-                price: dex_price,
-                timestamp: env.ledger().timestamp(),
-                confidence: 85,
-            });
+    /// Pull token_in from the user, swap it (with protocol fee) and supply the output to Blend
+    fn zap_into_blend(
+        env: &Env,
+        user: &Address,
+        token_in: &Address,
+        target_asset: &Address,
+        amount: u128,
+        min_out: u128,
+    ) -> (BlendPool, u128, u128) {
+        assert!(Self::is_asset_supported(env, token_in), "Token not supported");
+        assert!(Self::is_asset_supported(env, target_asset), "Asset not supported");
+        assert!(amount > 0, "Amount must be positive");
+        
+        Self::record_twap_observation(env, token_in);
+        Self::record_twap_observation(env, target_asset);
+        
+        Self::transfer_from_user(env, token_in, user, &env.current_contract_address(), amount);
+        
+        // Depositing the target asset directly skips the swap
+        let supplied = if token_in == target_asset {
+            amount
+        } else {
+            let swap = SwapParams {
+                token_a: token_in.clone(),
+                token_b: target_asset.clone(),
+                amount_in: amount,
+                min_amount_out: min_out,
+            };
+            let (amount_out, _) = Self::execute_swap_with_fees(env, user, &swap, &None, symbol_short!("zap"));
+            assert!(amount_out >= min_out, "Slippage exceeded");
+            amount_out
+        };
+        
+        let (blend_pool, btokens_received) = Self::supply_for_user(env, user, target_asset, supplied);
+        Self::record_health_snapshot(env, user);
+        (blend_pool, supplied, btokens_received)
+    }
+
+    /// Borrow from Blend into the hub on a user's behalf; callers check health
+    fn borrow_for_user(env: &Env, user: &Address, asset: &Address, amount: u128) {
+        // Borrowing is paused while the asset's or any collateral's circuit breaker is tripped
+        if Self::is_price_paused(env, asset) {
+            panic_with_error!(env, HubError::BorrowPaused);
         }
+        Self::assert_collateral_prices_live(env, user, HubError::BorrowPaused);
+        let blend_pool = Self::get_or_create_blend_pool(env, asset);
+        Self::borrow_from_blend_pool(env, &blend_pool, asset, amount);
+        Self::update_user_borrow_position(env, user, asset, amount);
+    }
+
+    /// Supply funds held by the hub to Blend on a user's behalf
+    fn supply_for_user(env: &Env, user: &Address, asset: &Address, amount: u128) -> (BlendPool, u128) {
+        let blend_pool = Self::get_or_create_blend_pool(env, asset);
+        let btokens_received = Self::supply_to_blend_pool(env, &blend_pool, asset, amount);
+        Self::update_user_supply_position(env, user, asset, amount);
         
-        if let Some(admin_price) = Self::get_admin_price(env, asset) {
-            price_sources.push_back(PriceSource {
-                source_type: String::from_str(env, "admin"),
-                price: admin_price,
-                timestamp: env.ledger().timestamp(),
-                confidence: 70,
-            });
+        let mut position = Self::get_user_position(env.clone(), user.clone());
+        let issued = position.supplied_btokens.get(asset.clone()).unwrap_or(0);
+        position.supplied_btokens.set(asset.clone(), issued + btokens_received);
+        Self::save_user_position(env, user, &position);
+        (blend_pool, btokens_received)
+    }
+
+    /// Repay part of a user's debt to Blend from funds held by the hub
+    fn repay_for_user(env: &Env, user: &Address, asset: &Address, amount: u128) {
+        let blend_pool = Self::get_or_create_blend_pool(env, asset);
+        Self::repay_blend_pool(env, &blend_pool, asset, amount);
+        Self::reduce_user_borrow_position(env, user, asset, amount);
+    }
+
+    /// Reject a borrow that isolation, e-mode or debt ceiling rules forbid, before any funds move
+    fn check_borrow_rules(env: &Env, user: &Address, asset: &Address, amount: u128) {
+        Self::calculate_health_factor(env.clone(), user.clone(), Some((asset.clone(), amount)));
+    }
+
+    /// Withdraw collateral, swap it into the debt asset and repay up to `max_repay`, sending any
+    /// surplus output to the user. Intermediate states are never checked; callers check the final
+    /// position. Returns the bTokens taken back and the amount repaid.
+    fn sell_collateral_for_debt(
+        env: &Env,
+        user: &Address,
+        collateral_asset: &Address,
+        debt_asset: &Address,
+        collateral_in: u128,
+        min_out: u128,
+        max_repay: u128,
+    ) -> (u128, u128) {
+        let btokens_returned = Self::withdraw_for_user(env, user, collateral_asset, collateral_in);
+        let amount_out = Self::execute_soroswap(env, collateral_asset, debt_asset, collateral_in, min_out);
+        assert!(amount_out >= min_out, "Slippage exceeded");
+        let repaid = amount_out.min(max_repay);
+        Self::repay_for_user(env, user, debt_asset, repaid);
+        if amount_out > repaid {
+            Self::transfer_to_user(env, debt_asset, user, amount_out - repaid);
         }
+        (btokens_returned, repaid)
+    }
 
-        if let Some(mock_price) = Self::get_mock_price(env, asset) {
-            price_sources.push_back(PriceSource {
-                source_type: String::from_str(env, "mock"),
-                price: mock_price,
-                timestamp: env.ledger().timestamp(),
-                confidence: 50,
-            });
+    fn get_asset_price_safe(env: &Env, asset: &Address) -> Result<u128, HubError> {
+        let price_sources = Self::collect_price_sources(env, asset);
+        let config = Self::get_aggregation_config(env.clone(), Some(asset.clone()));
+        let source = if price_sources.len() == 1 {
+            price_sources.get(0).unwrap().source_type
+        } else {
+            match config.mode {
+                AggregationMode::Median => String::from_str(env, "median"),
+                AggregationMode::WeightedMean => String::from_str(env, "weighted_mean"),
+            }
+        };
+        // Aggregate fresh sources, rejecting outliers
+        let price = Self::select_best_price(env, price_sources, &config)?;
+        let (price, accepted) = Self::apply_circuit_breaker(env, asset, price);
+        if accepted {
+            Self::record_price_history(env, asset, price, source);
+        }
+        Ok(price)
+    }
+
+    /// Accept a price unless it moved too far within the window; tripped assets stay frozen.
+    /// Returns the price to use and whether it was newly accepted.
+    fn apply_circuit_breaker(env: &Env, asset: &Address, price: u128) -> (u128, bool) {
+        let key = (symbol_short!("prc_stat"), asset.clone());
+        let current_time = env.ledger().timestamp();
+        let config = Self::get_circuit_breaker_config(env.clone());
+        
+        let mut status: PriceStatus = match env.storage().persistent().get(&key) {
+            Some(status) => status,
+            None => {
+                let status = PriceStatus {
+                    last_price: price,
+                    last_update: current_time,
+                    reference_price: price,
+                    window_start: current_time,
+                    tripped: false,
+                    tripped_at: 0,
+                };
+                env.storage().persistent().set(&key, &status);
+                return (price, true);
+            }
+        };
+        
+        if status.tripped {
+            if current_time < status.tripped_at + config.cooldown {
+                return (status.last_price, false);
+            }
+            // Cooldown over: the current price becomes the new reference
+            status.tripped = false;
+            status.reference_price = price;
+            status.window_start = current_time;
+            env.events().publish(
+                (symbol_short!("cb_reset"), asset.clone()),
+                price
+            );
+        } else if current_time >= status.window_start + config.window {
+            status.reference_price = status.last_price;
+            status.window_start = current_time;
+        }
+        
+        let change = price.abs_diff(status.reference_price);
+        if status.reference_price > 0 && change * 10000 / status.reference_price > config.max_change {
+            status.tripped = true;
+            status.tripped_at = current_time;
+            env.storage().persistent().set(&key, &status);
+            env.events().publish(
+                (Symbol::new(env, "circuit_break"), asset.clone()),
+                (status.reference_price, price, status.last_price)
+            );
+            return (status.last_price, false);
+        }
+        
+        status.last_price = price;
+        status.last_update = current_time;
+        env.storage().persistent().set(&key, &status);
+        (price, true)
+    }
+
+    /// Whether an asset's circuit breaker is tripped and still cooling down
+    fn is_price_paused(env: &Env, asset: &Address) -> bool {
+        let status = Self::get_price_status(env.clone(), asset.clone());
+        let config = Self::get_circuit_breaker_config(env.clone());
+        status.tripped && env.ledger().timestamp() < status.tripped_at + config.cooldown
+    }
+
+    /// Tripped collateral is valued at its frozen pre-trip price, so nothing may be drawn against it
+    fn assert_collateral_prices_live(env: &Env, user: &Address, error: HubError) {
+        let position = Self::get_user_position(env.clone(), user.clone());
+        for (asset, amount) in position.supplied_assets.iter() {
+            let enabled = position.collateral_enabled.get(asset.clone()).unwrap_or(true);
+            if amount > 0 && enabled && Self::is_price_paused(env, &asset) {
+                let is_collateral = Self::get_asset_config(env, &asset).map(|c| c.is_collateral).unwrap_or(false);
+                if is_collateral {
+                    panic_with_error!(env, error);
+                }
+            }
+        }
+    }
+
+    /// Query every configured source for an asset, keeping fresh prices only
+    fn collect_price_sources(env: &Env, asset: &Address) -> Vec<PriceSource> {
+        let mut price_sources = Vec::new(env);
+        let current_time = env.ledger().timestamp();
+        
+        for source in Self::get_oracle_sources(env.clone(), asset.clone()).iter() {
+            if let Some((price, timestamp)) = Self::fetch_source_price(env, asset, &source) {
+                if current_time.saturating_sub(timestamp) <= source.max_age {
+                    price_sources.push_back(PriceSource {
+                        source_type: Self::source_label(env, &source.source_type),
+                        price,
+                        timestamp,
+                        confidence: source.confidence,
+                    });
+                }
+            }
+        }
+        price_sources
+    }
+
+    fn fetch_source_price(env: &Env, asset: &Address, source: &OracleSource) -> Option<(u128, u64)> {
+        match source.source_type {
+            OracleSourceType::Dia => {
+                let oracle_address = match &source.address {
+                    Some(address) => address.clone(),
+                    None => {
+                        let oracle_config: DIAOracleConfig = env.storage().instance().get(&symbol_short!("oracle"))?;
+                        oracle_config.oracle_address
+                    }
+                };
+                Self::try_dia_oracle(env, asset, &oracle_address, source.decimals)
+            }
+            OracleSourceType::Sep40 => {
+                let oracle_address = source.address.clone()?;
+                // Symbol-keyed feeds, such as Reflector's BTC and ETH, are queried as Other
+                let feed = match &source.feed_symbol {
+                    Some(symbol) => Sep40Asset::Other(symbol.clone()),
+                    None => Sep40Asset::Stellar(asset.clone()),
+                };
+                Self::try_sep40_oracle(env, asset, &oracle_address, source.decimals, feed)
+            }
+            OracleSourceType::DexTwap => Self::get_dex_twap(env, asset),
+            OracleSourceType::Admin => Self::get_admin_price(env, asset),
+            #[cfg(feature = "mock-oracle")]
+            OracleSourceType::Mock => Self::get_mock_price(env, asset)
+                .map(|price| (price, env.ledger().timestamp())),
+            #[cfg(not(feature = "mock-oracle"))]
+            OracleSourceType::Mock => None,
+        }
+    }
+
+    fn source_label(env: &Env, source_type: &OracleSourceType) -> String {
+        match source_type {
+            OracleSourceType::Dia => String::from_str(env, "dia"),
+            OracleSourceType::Sep40 => String::from_str(env, "sep40"),
+            OracleSourceType::DexTwap => String::from_str(env, "dex_twap"),
+            OracleSourceType::Admin => String::from_str(env, "admin"),
+            OracleSourceType::Mock => String::from_str(env, "mock"),
+        }
+    }
+
+    fn default_oracle_sources(env: &Env) -> Vec<OracleSource> {
+        #[allow(unused_mut)]
+        let mut sources = soroban_sdk::vec![
+            env,
+            OracleSource { source_type: OracleSourceType::Dia, address: None, confidence: 90, max_age: MAX_PRICE_AGE, decimals: 8, feed_symbol: None },
+            OracleSource { source_type: OracleSourceType::DexTwap, address: None, confidence: 85, max_age: MAX_PRICE_AGE, decimals: 0, feed_symbol: None },
+            // Emergency prices carry their own expiry; max_age only needs to cover the longest allowed
+            OracleSource { source_type: OracleSourceType::Admin, address: None, confidence: 70, max_age: MAX_EMERGENCY_PRICE_DURATION, decimals: 0, feed_symbol: None },
+        ];
+        #[cfg(feature = "mock-oracle")]
+        sources.push_back(OracleSource { source_type: OracleSourceType::Mock, address: None, confidence: 50, max_age: MAX_PRICE_AGE, decimals: 0, feed_symbol: None });
+        sources
+    }
+
+    fn save_oracle_sources(env: &Env, asset: &Address, sources: &Vec<OracleSource>) {
+        for source in sources.iter() {
+            assert!(source.confidence <= 100, "Confidence must be 0-100");
+            assert!(source.max_age > 0, "Max age must be positive");
+            if source.source_type == OracleSourceType::Sep40 {
+                assert!(source.address.is_some(), "SEP-40 source requires an oracle address");
+            }
+            #[cfg(not(feature = "mock-oracle"))]
+            assert!(source.source_type != OracleSourceType::Mock, "Mock prices are disabled in this build");
+        }
+        env.storage().persistent().set(&(symbol_short!("orc_srcs"), asset.clone()), sources);
+    }
+
+    fn oracle_quote(env: &Env, token_in: &Address, token_out: &Address, amount_in: u128) -> Result<u128, HubError> {
+        // Prices are quoted in each asset's own decimals:
+        // amount_out = amount_in * price_in * precision_out^2 / (price_out * precision_in^2)
+        let price_in = Self::get_asset_price_safe(env, token_in)?;
+        let price_out = Self::get_asset_price_safe(env, token_out)?;
+        let precision_in = Self::get_price_precision(env, token_in)?;
+        let precision_out = Self::get_price_precision(env, token_out)?;
+        if price_out == 0 {
+            return Err(HubError::OracleFailure);
         }
-        // Select best price source
-        Self::select_best_price(env, price_sources)
+        
+        // Multiply out in 256 bits and divide once; precisions are powers of ten so their ratio is exact
+        let wide = |value: u128| U256::from_u128(env, value);
+        let mut numerator = wide(amount_in).mul(&wide(price_in));
+        let mut denominator = wide(price_out);
+        if precision_out >= precision_in {
+            let scale = wide(precision_out / precision_in);
+            numerator = numerator.mul(&scale).mul(&scale);
+        } else {
+            let scale = wide(precision_in / precision_out);
+            denominator = denominator.mul(&scale).mul(&scale);
+        }
+        let amount_out = numerator.div(&denominator).to_u128().ok_or(HubError::OracleFailure)?;
+        
+        // A zero quote would leave callers with no slippage bound
+        if amount_out == 0 {
+            return Err(HubError::OracleFailure);
+        }
+        Ok(amount_out)
+    }
+
+    /// Oracle estimate of token_in needed to buy amount_out of token_out, with a slippage buffer
+    fn estimate_amount_in(env: &Env, token_in: &Address, token_out: &Address, amount_out: u128) -> Result<u128, HubError> {
+        let quote = Self::oracle_quote(env, token_out, token_in, amount_out)?;
+        Ok(quote.checked_mul(10000 + SWAP_ESTIMATE_BUFFER).ok_or(HubError::OracleFailure)? / 10000)
     }
 
     fn get_asset_config(env: &Env, asset: &Address) -> Result<AssetConfig, HubError> {
@@ -622,6 +2997,100 @@ impl StellarDeFiHub {
         Err(HubError::AssetNotSupported)
     }
 
+    /// The user's emode category, if their debt (including a pending borrow) is all inside it
+    fn get_active_emode(
+        env: &Env,
+        position: &UserPosition,
+        additional_borrow: &Option<(Address, u128)>,
+    ) -> Result<Option<EModeCategory>, HubError> {
+        if position.emode_category == 0 {
+            return Ok(None);
+        }
+        let category: EModeCategory = match env.storage().instance().get(&(symbol_short!("emode"), position.emode_category)) {
+            Some(category) => category,
+            None => return Ok(None),
+        };
+        if let Some((borrow_asset, _)) = additional_borrow {
+            if Self::get_asset_config(env, borrow_asset)?.emode_category != category.id {
+                return Err(HubError::EModeViolation);
+            }
+        }
+        for (asset, _) in position.borrowed_assets.iter() {
+            if Self::get_asset_config(env, &asset)?.emode_category != category.id {
+                return Ok(None);
+            }
+        }
+        Ok(Some(category))
+    }
+
+    fn save_asset_config(env: &Env, config: &AssetConfig) {
+        for i in 0..10 {
+            let key = (symbol_short!("asset"), i);
+            if let Some(existing) = env.storage().instance().get::<_, AssetConfig>(&key) {
+                if existing.address == config.address {
+                    env.storage().instance().set(&key, config);
+                    return;
+                }
+            }
+        }
+        panic_with_error!(env, HubError::AssetNotSupported);
+    }
+
+    /// Track stablecoin debt against the user's isolated collateral, if any
+    fn update_isolated_debt(env: &Env, user: &Address, asset: &Address, amount: u128, is_borrow: bool) {
+        // Only stablecoin borrows count towards a debt ceiling, as in calculate_position_values
+        let is_stablecoin = Self::get_asset_config(env, asset).map(|config| config.is_stablecoin).unwrap_or(false);
+        if !is_stablecoin {
+            return;
+        }
+        let mut position = Self::get_user_position(env.clone(), user.clone());
+        // Debt stays attributed to the isolated asset it was opened against, even if collateral rotates
+        let isolation_asset = match position.isolation_asset.clone() {
+            Some(asset) => asset,
+            None if is_borrow => {
+                let mut current = None;
+                for (asset, supplied) in position.supplied_assets.iter() {
+                    let enabled = position.collateral_enabled.get(asset.clone()).unwrap_or(true);
+                    if let Ok(config) = Self::get_asset_config(env, &asset) {
+                        if config.is_isolated && config.is_collateral && enabled && supplied > 0 {
+                            current = Some(asset);
+                            break;
+                        }
+                    }
+                }
+                match current {
+                    Some(asset) => asset,
+                    None => return,
+                }
+            }
+            None => return,
+        };
+        
+        // Repayments only release this user's share of the ceiling
+        let change = if is_borrow { amount } else { amount.min(position.isolated_debt) };
+        let key = (symbol_short!("iso_debt"), isolation_asset.clone());
+        let current: u128 = env.storage().persistent().get(&key).unwrap_or(0);
+        let updated = if is_borrow { current + change } else { current.saturating_sub(change) };
+        env.storage().persistent().set(&key, &updated);
+        
+        position.isolated_debt = if is_borrow { position.isolated_debt + change } else { position.isolated_debt - change };
+        position.isolation_asset = if position.isolated_debt > 0 { Some(isolation_asset) } else { None };
+        Self::save_user_position(env, user, &position);
+    }
+
+    fn get_asset_value(env: &Env, asset: &Address, amount: u128) -> Result<u128, HubError> {
+        let price = Self::get_asset_price_safe(env, asset)?;
+        Ok((amount * price) / Self::get_price_precision(env, asset)?)
+    }
+
+    fn value_to_amount(env: &Env, asset: &Address, value: u128) -> Result<u128, HubError> {
+        let price = Self::get_asset_price_safe(env, asset)?;
+        if price == 0 {
+            return Err(HubError::OracleFailure);
+        }
+        Ok((value * Self::get_price_precision(env, asset)?) / price)
+    }
+
     fn get_price_precision(env: &Env, asset: &Address) -> Result<u128, HubError> {
         let config = Self::get_asset_config(env, asset)?;
         Ok(10u128.pow(config.decimals))
@@ -645,6 +3114,110 @@ impl StellarDeFiHub {
         env.storage().persistent().set(&key, &(current + amount));
     }
 
+    fn fee_tier_key(token_a: &Address, token_b: &Address) -> (Symbol, Address, Address) {
+        // Pair tiers are direction-agnostic
+        if token_a < token_b {
+            (symbol_short!("fee_tier"), token_a.clone(), token_b.clone())
+        } else {
+            (symbol_short!("fee_tier"), token_b.clone(), token_a.clone())
+        }
+    }
+
+    fn record_swap_volume(env: &Env, user: &Address, token: &Address, amount: u128) {
+        // Volume is valued in VOLUME_PRECISION units so different input tokens are comparable;
+        // amounts and prices are both in the token's decimals, hence the precision^2 divisor
+        let price = match Self::get_asset_price_safe(env, token) {
+            Ok(price) => price,
+            Err(_) => return,
+        };
+        let precision = match Self::get_price_precision(env, token) {
+            Ok(precision) => precision,
+            Err(_) => return,
+        };
+        let precision_sq = match precision.checked_mul(precision) {
+            Some(precision_sq) => precision_sq,
+            None => return,
+        };
+        // The product of two u128 values always fits in 256 bits
+        let raw = U256::from_u128(env, amount).mul(&U256::from_u128(env, price));
+        let value = if precision_sq >= VOLUME_PRECISION {
+            raw.div(&U256::from_u128(env, precision_sq / VOLUME_PRECISION)).to_u128()
+        } else {
+            raw.to_u128().and_then(|raw| raw.checked_mul(VOLUME_PRECISION / precision_sq))
+        };
+        // Skip the update rather than fail the swap on overflow
+        let value = match value {
+            Some(value) => value,
+            None => return,
+        };
+        
+        let key = (symbol_short!("volume"), user.clone());
+        let stored: Map<u64, u128> = env.storage().persistent().get(&key).unwrap_or(Map::new(env));
+        let today = env.ledger().timestamp() / SECONDS_PER_DAY;
+        
+        // Drop daily buckets that fell out of the rolling window
+        let mut buckets = Map::new(env);
+        for (day, amount) in stored.iter() {
+            if day + VOLUME_WINDOW_DAYS > today {
+                buckets.set(day, amount);
+            }
+        }
+        
+        let current = buckets.get(today).unwrap_or(0);
+        buckets.set(today, current.saturating_add(value));
+        env.storage().persistent().set(&key, &buckets);
+    }
+
+    fn accrue_referral_fee(env: &Env, referrer: &Address, token: &Address, fee_amount: u128) -> u128 {
+        let share = Self::get_referral_share(env.clone());
+        let amount = (fee_amount * share) / 10000;
+        if amount == 0 {
+            return 0;
+        }
+        
+        let mut account = Self::get_referral_account(env.clone(), referrer.clone());
+        let accrued = account.accrued.get(token.clone()).unwrap_or(0);
+        let earned = account.total_earned.get(token.clone()).unwrap_or(0);
+        account.accrued.set(token.clone(), accrued + amount);
+        account.total_earned.set(token.clone(), earned + amount);
+        account.referred_swaps += 1;
+        env.storage().persistent().set(&(symbol_short!("referral"), referrer.clone()), &account);
+        
+        env.events().publish(
+            (symbol_short!("ref_fee"), referrer),
+            (token.clone(), amount)
+        );
+        
+        amount
+    }
+
+    fn remove_open_order(env: &Env, user: &Address, order_id: u64) {
+        let key = (symbol_short!("u_orders"), user.clone());
+        let mut open_orders: Vec<u64> = env.storage().persistent().get(&key).unwrap_or(Vec::new(env));
+        if let Some(index) = open_orders.first_index_of(order_id) {
+            open_orders.remove(index);
+            env.storage().persistent().set(&key, &open_orders);
+        }
+    }
+
+    fn remove_user_dca(env: &Env, user: &Address, dca_id: u64) {
+        let key = (symbol_short!("u_dca"), user.clone());
+        let mut schedules: Vec<u64> = env.storage().persistent().get(&key).unwrap_or(Vec::new(env));
+        if let Some(index) = schedules.first_index_of(dca_id) {
+            schedules.remove(index);
+            env.storage().persistent().set(&key, &schedules);
+        }
+    }
+
+    fn remove_conditional_order(env: &Env, user: &Address, order_id: u64) {
+        let key = (symbol_short!("u_cond"), user.clone());
+        let mut open_orders: Vec<u64> = env.storage().persistent().get(&key).unwrap_or(Vec::new(env));
+        if let Some(index) = open_orders.first_index_of(order_id) {
+            open_orders.remove(index);
+            env.storage().persistent().set(&key, &open_orders);
+        }
+    }
+
     fn update_user_rewards(env: &Env, user: &Address, btoken: &Address) {
         let mut position = Self::get_user_position(env.clone(), user.clone());
         let current_time = env.ledger().timestamp();
@@ -698,6 +3271,13 @@ impl StellarDeFiHub {
         }
     }
 
+    fn stake_for_user(env: &Env, user: &Address, btoken: &Address, amount: u128) {
+        // Update user rewards before changing stake
+        Self::update_user_rewards(env, user, btoken);
+        Self::update_staking_position(env, user, btoken, amount, true);
+        Self::update_staking_pool(env, btoken, amount, true);
+    }
+
     /// Update staking pool state when users stake/unstake
     fn update_staking_pool(env: &Env, btoken: &Address, amount: u128, is_stake: bool) {
         let key = (symbol_short!("pool"), btoken);
@@ -762,36 +3342,93 @@ impl StellarDeFiHub {
         );
     }
 
-    fn transfer_to_user(
+    fn transfer_to_user(
+        env: &Env,
+        token: &Address,
+        user: &Address,
+        amount: u128,
+    ) {
+        env.invoke_contract::<()>(
+            token,
+            &symbol_short!("transfer"),
+            soroban_sdk::vec![
+                env,
+                user.into_val(env),
+                amount.into_val(env),
+            ],
+        );
+    }
+
+    fn token_balance(env: &Env, token: &Address, owner: &Address) -> u128 {
+        env.invoke_contract::<u128>(
+            token,
+            &symbol_short!("balance"),
+            soroban_sdk::vec![env, owner.into_val(env)],
+        )
+    }
+
+    fn update_user_supply_position(
+        env: &Env,
+        user: &Address,
+        asset: &Address,
+        amount: u128,
+    ) {
+        let mut position = Self::get_user_position(env.clone(), user.clone());
+        let current = position.supplied_assets.get(asset.clone()).unwrap_or(0);
+        position.supplied_assets.set(asset.clone(), current + amount);
+        Self::save_user_position(env, user, &position);
+        
+        let mut totals = Self::get_asset_totals(env.clone(), asset.clone());
+        totals.total_supplied += amount;
+        let config = Self::get_asset_config(env, asset).unwrap_or_else(|e| panic_with_error!(env, e));
+        if config.supply_cap > 0 && totals.total_supplied > config.supply_cap {
+            panic_with_error!(env, HubError::SupplyCapExceeded);
+        }
+        env.storage().persistent().set(&(symbol_short!("totals"), asset.clone()), &totals);
+    }
+
+    fn update_user_borrow_position(
         env: &Env,
-        token: &Address,
         user: &Address,
+        asset: &Address,
         amount: u128,
     ) {
-        env.invoke_contract::<()>(
-            token,
-            &symbol_short!("transfer"),
-            soroban_sdk::vec![
-                env,
-                user.into_val(env),
-                amount.into_val(env),
-            ],
-        );
+        let mut position = Self::get_user_position(env.clone(), user.clone());
+        let current = position.borrowed_assets.get(asset.clone()).unwrap_or(0);
+        position.borrowed_assets.set(asset.clone(), current + amount);
+        Self::save_user_position(env, user, &position);
+        Self::update_isolated_debt(env, user, asset, amount, true);
+        
+        let mut totals = Self::get_asset_totals(env.clone(), asset.clone());
+        totals.total_borrowed += amount;
+        let config = Self::get_asset_config(env, asset).unwrap_or_else(|e| panic_with_error!(env, e));
+        if config.borrow_cap > 0 && totals.total_borrowed > config.borrow_cap {
+            panic_with_error!(env, HubError::BorrowCapExceeded);
+        }
+        env.storage().persistent().set(&(symbol_short!("totals"), asset.clone()), &totals);
     }
 
-    fn update_user_supply_position(
+    fn reduce_user_supply_position(
         env: &Env,
         user: &Address,
         asset: &Address,
         amount: u128,
     ) {
         let mut position = Self::get_user_position(env.clone(), user.clone());
-        let current = position.supplied_assets.get(asset.clone()).unwrap_or(0);
-        position.supplied_assets.set(asset.clone(), current + amount);
+        let new_amount = position.supplied_assets.get(asset.clone()).unwrap_or(0).saturating_sub(amount);
+        if new_amount == 0 {
+            position.supplied_assets.remove(asset.clone());
+        } else {
+            position.supplied_assets.set(asset.clone(), new_amount);
+        }
         Self::save_user_position(env, user, &position);
+        
+        let mut totals = Self::get_asset_totals(env.clone(), asset.clone());
+        totals.total_supplied = totals.total_supplied.saturating_sub(amount);
+        env.storage().persistent().set(&(symbol_short!("totals"), asset.clone()), &totals);
     }
 
-    fn update_user_borrow_position(
+    fn reduce_user_borrow_position(
         env: &Env,
         user: &Address,
         asset: &Address,
@@ -799,8 +3436,18 @@ impl StellarDeFiHub {
     ) {
         let mut position = Self::get_user_position(env.clone(), user.clone());
         let current = position.borrowed_assets.get(asset.clone()).unwrap_or(0);
-        position.borrowed_assets.set(asset.clone(), current + amount);
+        let new_amount = current.saturating_sub(amount);
+        if new_amount == 0 {
+            position.borrowed_assets.remove(asset.clone());
+        } else {
+            position.borrowed_assets.set(asset.clone(), new_amount);
+        }
         Self::save_user_position(env, user, &position);
+        Self::update_isolated_debt(env, user, asset, current - new_amount, false);
+        
+        let mut totals = Self::get_asset_totals(env.clone(), asset.clone());
+        totals.total_borrowed = totals.total_borrowed.saturating_sub(amount);
+        env.storage().persistent().set(&(symbol_short!("totals"), asset.clone()), &totals);
     }
 
     fn save_user_position(
@@ -811,31 +3458,56 @@ impl StellarDeFiHub {
         env.storage().persistent().set(&(symbol_short!("pos"), user.clone()), position);
     }
 
-    fn try_dia_oracle(env: &Env, asset: &Address) -> Option<u128> {
-        let oracle_config: DIAOracleConfig = env.storage().instance().get(&symbol_short!("oracle"))?;
+    fn try_dia_oracle(env: &Env, asset: &Address, oracle_address: &Address, decimals: u32) -> Option<(u128, u64)> {
         let asset_config = match Self::get_asset_config(env, asset) {
             Ok(cfg) => cfg,
             Err(_) => return None,
         };
         let price_result = env.try_invoke_contract::<DIAPriceData, soroban_sdk::xdr::Error>(
-            &oracle_config.oracle_address,
+            oracle_address,
             &symbol_short!("getValue"),
             soroban_sdk::vec![env, asset_config.dia_symbol.into_val(env)],
         );
         if let Ok(Ok(price_data)) = price_result {
-            let price_age = env.ledger().timestamp() - price_data.timestamp;
-            if price_age <= oracle_config.max_price_age {
-                let normalized_price = if asset_config.decimals < 8 {
-                    price_data.price / (10u128.pow(8 - asset_config.decimals))
-                } else {
-                    price_data.price * (10u128.pow(asset_config.decimals - 8))
-                };
-                return Some(normalized_price);
+            let normalized_price = Self::normalize_price(price_data.price, decimals, asset_config.decimals);
+            return Some((normalized_price, price_data.timestamp));
+        }
+        None
+    }
+
+    fn try_sep40_oracle(
+        env: &Env,
+        asset: &Address,
+        oracle_address: &Address,
+        decimals: u32,
+        feed: Sep40Asset,
+    ) -> Option<(u128, u64)> {
+        let asset_config = match Self::get_asset_config(env, asset) {
+            Ok(cfg) => cfg,
+            Err(_) => return None,
+        };
+        let price_result = env.try_invoke_contract::<Option<PriceData>, soroban_sdk::xdr::Error>(
+            oracle_address,
+            &symbol_short!("lastprice"),
+            soroban_sdk::vec![env, feed.into_val(env)],
+        );
+        if let Ok(Ok(Some(price_data))) = price_result {
+            if price_data.price > 0 {
+                let normalized_price = Self::normalize_price(price_data.price as u128, decimals, asset_config.decimals);
+                return Some((normalized_price, price_data.timestamp));
             }
         }
         None
     }
 
+    fn normalize_price(price: u128, from_decimals: u32, to_decimals: u32) -> u128 {
+        if from_decimals > to_decimals {
+            price / (10u128.pow(from_decimals - to_decimals))
+        } else {
+            price * (10u128.pow(to_decimals - from_decimals))
+        }
+    }
+
     fn get_dex_price(env: &Env, asset: &Address) -> Option<u128> {
         let usdc_address = Address::from_string(&String::from_str(env, SUPPORTED_ASSETS[0].0));
         if *asset == usdc_address {
@@ -857,6 +3529,142 @@ impl StellarDeFiHub {
     }
 
 
+    fn record_price_history(env: &Env, asset: &Address, price: u128, source: String) {
+        let key = (symbol_short!("prc_hist"), asset.clone());
+        let mut history: Vec<PricePoint> = env.storage().persistent().get(&key).unwrap_or(Vec::new(env));
+        let current_time = env.ledger().timestamp();
+        
+        // One entry per ledger timestamp
+        if let Some(last) = history.last() {
+            if last.timestamp == current_time {
+                return;
+            }
+        }
+        if history.len() >= PRICE_HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(PricePoint {
+            timestamp: current_time,
+            price,
+            source,
+        });
+        env.storage().persistent().set(&key, &history);
+    }
+
+    fn record_health_snapshot(env: &Env, user: &Address) {
+        let health_factor = match Self::calculate_health_factor_internal(env.clone(), user.clone(), None) {
+            Ok(val) => val,
+            Err(_) => return,
+        };
+        let key = (symbol_short!("hf_hist"), user.clone());
+        let mut history: Vec<HealthSnapshot> = env.storage().persistent().get(&key).unwrap_or(Vec::new(env));
+        let current_time = env.ledger().timestamp();
+        
+        // Keep only the latest snapshot per ledger timestamp
+        if let Some(last) = history.last() {
+            if last.timestamp == current_time {
+                history.pop_back();
+            }
+        }
+        if history.len() >= HEALTH_HISTORY_LEN {
+            history.pop_front();
+        }
+        history.push_back(HealthSnapshot {
+            timestamp: current_time,
+            health_factor,
+        });
+        env.storage().persistent().set(&key, &history);
+    }
+
+    /// Record a spot DEX observation in the asset's ring buffer
+    fn record_twap_observation(env: &Env, asset: &Address) -> bool {
+        let key = (symbol_short!("twap"), asset.clone());
+        let mut buffer: TwapBuffer = env.storage().persistent().get(&key).unwrap_or(TwapBuffer {
+            observations: Vec::new(env),
+            next_index: 0,
+        });
+        let current_time = env.ledger().timestamp();
+        let price = match Self::get_dex_price(env, asset) {
+            Some(price) => price,
+            None => return false,
+        };
+        
+        let latest = Self::latest_twap_observation(&buffer);
+        let cumulative_price = match &latest {
+            Some(latest) => latest.cumulative_price + latest.price * (current_time - latest.timestamp) as u128,
+            None => 0,
+        };
+        let observation = TwapObservation {
+            timestamp: current_time,
+            price,
+            cumulative_price,
+        };
+        
+        // Every observation is accepted, so nobody can lock out the one that closes a
+        // manipulated interval. Slots stay TWAP_MIN_INTERVAL apart: until the latest slot
+        // is that far from the one before it, newer observations refresh it in place.
+        let len = buffer.observations.len();
+        let refresh = match &latest {
+            Some(latest) if latest.timestamp == current_time => true,
+            Some(latest) if len >= 2 => {
+                let previous = buffer.observations.get((buffer.next_index + len - 2) % len).unwrap();
+                latest.timestamp < previous.timestamp + TWAP_MIN_INTERVAL
+            }
+            _ => false,
+        };
+        if refresh {
+            buffer.observations.set((buffer.next_index + len - 1) % len, observation);
+        } else {
+            if len < TWAP_CAPACITY {
+                buffer.observations.push_back(observation);
+            } else {
+                buffer.observations.set(buffer.next_index, observation);
+            }
+            buffer.next_index = (buffer.next_index + 1) % TWAP_CAPACITY;
+        }
+        env.storage().persistent().set(&key, &buffer);
+        true
+    }
+
+    fn latest_twap_observation(buffer: &TwapBuffer) -> Option<TwapObservation> {
+        let len = buffer.observations.len();
+        if len == 0 {
+            return None;
+        }
+        buffer.observations.get((buffer.next_index + len - 1) % len)
+    }
+
+    /// Time-weighted DEX price over the configured window, with the latest observation time
+    fn get_dex_twap(env: &Env, asset: &Address) -> Option<(u128, u64)> {
+        let buffer: TwapBuffer = env.storage().persistent().get(&(symbol_short!("twap"), asset.clone()))?;
+        let latest = Self::latest_twap_observation(&buffer)?;
+        let current_time = env.ledger().timestamp();
+        let window_start = current_time.saturating_sub(Self::get_twap_window(env.clone()));
+        
+        // Newest observation at or before the window start anchors the average
+        let mut anchor: Option<TwapObservation> = None;
+        for observation in buffer.observations.iter() {
+            if observation.timestamp <= window_start {
+                let newer = match &anchor {
+                    Some(current) => observation.timestamp > current.timestamp,
+                    None => true,
+                };
+                if newer {
+                    anchor = Some(observation);
+                }
+            }
+        }
+        let anchor = anchor?;
+        
+        // The latest observation's interval is still open: its spot price only counts
+        // once a later observation closes it, so a same-transaction pool move has no weight
+        let elapsed = latest.timestamp - anchor.timestamp;
+        if elapsed == 0 {
+            return None;
+        }
+        Some(((latest.cumulative_price - anchor.cumulative_price) / elapsed as u128, latest.timestamp))
+    }
+
     fn simulate_dex_swap(
         env: &Env,
         token_in: &Address,
@@ -884,18 +3692,17 @@ impl StellarDeFiHub {
         None
     }
 
-    fn get_admin_price(env: &Env, asset: &Address) -> Option<u128> {
+    fn get_admin_price(env: &Env, asset: &Address) -> Option<(u128, u64)> {
+        // Served until its explicit expiry; the admin source's max_age also applies
         let key = (symbol_short!("price"), asset);
-        let price_data = env.storage().persistent().get::<_, (u128, u64)>(&key)?;
-        let (price, set_time) = price_data;
-        let age = env.ledger().timestamp() - set_time;
-        if age <= 86400 {
-            Some(price)
-        } else {
-            None
+        let (price, set_time, expires_at) = env.storage().persistent().get::<_, (u128, u64, u64)>(&key)?;
+        if env.ledger().timestamp() > expires_at {
+            return None;
         }
+        Some((price, set_time))
     }
 
+    #[cfg(feature = "mock-oracle")]
     fn get_mock_price(env: &Env, asset: &Address) -> Option<u128> {
         let asset_config = match Self::get_asset_config(env, asset) {
             Ok(cfg) => cfg,
@@ -927,49 +3734,75 @@ impl StellarDeFiHub {
         } else {
             return None;
         };
-        let normalized_price = if asset_config.decimals < 8 {
-            price / (10u128.pow(8 - asset_config.decimals))
-        } else {
-            price * (10u128.pow(asset_config.decimals - 8))
-        };
-        Some(normalized_price)
+        Some(Self::normalize_price(price, 8, asset_config.decimals))
     }
 
-    fn select_best_price(_env: &Env, price_sources: Vec<PriceSource>) -> Option<u128> {
+    fn select_best_price(
+        env: &Env,
+        price_sources: Vec<PriceSource>,
+        config: &AggregationConfig,
+    ) -> Result<u128, HubError> {
+        // Only fresh sources reach this point
         if price_sources.is_empty() {
-            return None;
+            return Err(HubError::PriceStale);
+        }
+        let mut prices = Vec::new(env);
+        for source in price_sources.iter() {
+            prices.push_back(source.price);
+        }
+        let median = Self::median_price(&prices);
+        
+        // Keep sources within max_deviation of the median
+        let mut agreeing = Vec::new(env);
+        for source in price_sources.iter() {
+            let diff = source.price.abs_diff(median);
+            if median > 0 && diff * 10000 / median <= config.max_deviation {
+                agreeing.push_back(source);
+            }
         }
-        if price_sources.len() == 1 {
-            return Some(price_sources.get(0).unwrap().price);
+        if agreeing.len() < config.min_sources.max(1) {
+            return Err(HubError::OracleFailure);
         }
-        let mut sorted_sources = price_sources.clone();
-        for i in 0..sorted_sources.len() {
-            for j in (i + 1)..sorted_sources.len() {
-                if sorted_sources.get(i).unwrap().confidence < sorted_sources.get(j).unwrap().confidence {
-                    let temp = sorted_sources.get(i).unwrap();
-                    sorted_sources.set(i, sorted_sources.get(j).unwrap());
-                    sorted_sources.set(j, temp);
+        
+        match config.mode {
+            AggregationMode::Median => {
+                let mut agreeing_prices = Vec::new(env);
+                for source in agreeing.iter() {
+                    agreeing_prices.push_back(source.price);
+                }
+                Ok(Self::median_price(&agreeing_prices))
+            }
+            AggregationMode::WeightedMean => {
+                let mut weighted_sum = 0u128;
+                let mut total_confidence = 0u128;
+                for source in agreeing.iter() {
+                    weighted_sum += source.price * source.confidence as u128;
+                    total_confidence += source.confidence as u128;
+                }
+                if total_confidence == 0 {
+                    return Err(HubError::OracleFailure);
                 }
+                Ok(weighted_sum / total_confidence)
             }
         }
-        let best = sorted_sources.get(0).unwrap();
-        if sorted_sources.len() == 1 || best.confidence >= 90 {
-            return Some(best.price);
+    }
+
+    fn median_price(prices: &Vec<u128>) -> u128 {
+        let mut sorted = prices.clone();
+        for i in 0..sorted.len() {
+            for j in (i + 1)..sorted.len() {
+                if sorted.get(i).unwrap() > sorted.get(j).unwrap() {
+                    let temp = sorted.get(i).unwrap();
+                    sorted.set(i, sorted.get(j).unwrap());
+                    sorted.set(j, temp);
+                }
+            }
         }
-        let second_best = sorted_sources.get(1).unwrap();
-        let price_diff = if best.price > second_best.price {
-            (best.price - second_best.price) * 100 / best.price
+        let mid = sorted.len() / 2;
+        if sorted.len().is_multiple_of(2) {
+            (sorted.get(mid - 1).unwrap() + sorted.get(mid).unwrap()) / 2
         } else {
-            (second_best.price - best.price) * 100 / second_best.price
-        };
-        if price_diff <= 5 {
-            let total_confidence = best.confidence + second_best.confidence;
-            let weighted_price = (best.price * best.confidence as u128 +
-                                 second_best.price * second_best.confidence as u128) /
-                                 total_confidence as u128;
-            Some(weighted_price)
-        } else {
-            Some(best.price)
+            sorted.get(mid).unwrap()
         }
     }
 
@@ -983,53 +3816,359 @@ impl StellarDeFiHub {
         admin: Address,
         asset: Address,
         price: u128,
+        expires_at: u64,
     ) {
         admin.require_auth();
         let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
         assert_eq!(admin, stored_admin, "Only admin can set emergency prices");
         assert!(Self::is_asset_supported(&env, &asset), "Asset not supported");
+        let current_time = env.ledger().timestamp();
+        assert!(expires_at > current_time, "Expiry must be in the future");
+        assert!(expires_at - current_time <= MAX_EMERGENCY_PRICE_DURATION, "Expiry too far in the future");
         let key = (symbol_short!("price"), asset.clone());
-        env.storage().persistent().set(&key, &(price, env.ledger().timestamp()));
+        env.storage().persistent().set(&key, &(price, current_time, expires_at));
         env.events().publish(
             (symbol_short!("emrgprc"), &admin),
-            (asset, price, env.ledger().timestamp())
+            (asset, price, current_time, expires_at)
+        );
+    }
+
+    /// Admin function to set the max age for every source of one type on an asset
+    pub fn set_source_max_age(
+        env: Env,
+        admin: Address,
+        asset: Address,
+        source_type: OracleSourceType,
+        max_age: u64,
+    ) {
+        admin.require_auth();
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can configure price sources");
+        assert!(Self::is_asset_supported(&env, &asset), "Asset not supported");
+        
+        let mut sources = Self::get_oracle_sources(env.clone(), asset.clone());
+        let mut updated = 0u32;
+        for i in 0..sources.len() {
+            let mut source = sources.get(i).unwrap();
+            if source.source_type == source_type {
+                source.max_age = max_age;
+                sources.set(i, source);
+                updated += 1;
+            }
+        }
+        assert!(updated > 0, "No source of that type configured");
+        Self::save_oracle_sources(&env, &asset, &sources);
+        
+        env.events().publish(
+            (symbol_short!("src_age"), &admin),
+            (asset, source_type, max_age)
         );
     }
 
+    /// Get the age of each configured source's latest price, so monitors can spot stale feeds
+    pub fn get_price_freshness(env: Env, asset: Address) -> Vec<SourceFreshness> {
+        let mut freshness = Vec::new(&env);
+        let current_time = env.ledger().timestamp();
+        
+        for source in Self::get_oracle_sources(env.clone(), asset.clone()).iter() {
+            let entry = match Self::fetch_source_price(&env, &asset, &source) {
+                Some((_, timestamp)) => {
+                    let age = current_time.saturating_sub(timestamp);
+                    SourceFreshness {
+                        source_type: source.source_type.clone(),
+                        available: true,
+                        last_update: timestamp,
+                        age,
+                        max_age: source.max_age,
+                        is_fresh: age <= source.max_age,
+                    }
+                }
+                None => SourceFreshness {
+                    source_type: source.source_type.clone(),
+                    available: false,
+                    last_update: 0,
+                    age: 0,
+                    max_age: source.max_age,
+                    is_fresh: false,
+                },
+            };
+            freshness.push_back(entry);
+        }
+        freshness
+    }
+
+    /// Get current prices from the asset's configured sources
     pub fn get_price_sources(env: Env, asset: Address) -> Vec<PriceSource> {
-        let mut sources = Vec::new(&env);
-        if let Some(oracle_price) = Self::try_dia_oracle(&env, &asset) {
-            sources.push_back(PriceSource {
-                source_type: String::from_str(&env, "oracle"),
-                price: oracle_price,
-                timestamp: env.ledger().timestamp(),
-                confidence: 90,
-            });
+        Self::collect_price_sources(&env, &asset)
+    }
+
+    /// Get accepted prices for an asset from timestamp `from` onwards, oldest first
+    pub fn get_price_history(env: Env, asset: Address, from: u64, limit: u32) -> Vec<PricePoint> {
+        let history: Vec<PricePoint> = env.storage()
+            .persistent()
+            .get(&(symbol_short!("prc_hist"), asset))
+            .unwrap_or(Vec::new(&env));
+        
+        let mut points = Vec::new(&env);
+        for point in history.iter() {
+            if points.len() >= limit {
+                break;
+            }
+            if point.timestamp >= from {
+                points.push_back(point);
+            }
         }
-        if let Some(dex_price) = Self::get_dex_price(&env, &asset) {
-            sources.push_back(PriceSource {
-                source_type: String::from_str(&env, "dex"),
-                price: dex_price,
-                timestamp: env.ledger().timestamp(),
-                confidence: 85,
-            });
+        points
+    }
+
+    /// Get a user's health factor snapshots from timestamp `from` onwards, oldest first
+    pub fn get_health_history(env: Env, user: Address, from: u64, limit: u32) -> Vec<HealthSnapshot> {
+        let history: Vec<HealthSnapshot> = env.storage()
+            .persistent()
+            .get(&(symbol_short!("hf_hist"), user))
+            .unwrap_or(Vec::new(&env));
+        
+        let mut snapshots = Vec::new(&env);
+        for snapshot in history.iter() {
+            if snapshots.len() >= limit {
+                break;
+            }
+            if snapshot.timestamp >= from {
+                snapshots.push_back(snapshot);
+            }
         }
-        if let Some(admin_price) = Self::get_admin_price(&env, &asset) {
-            sources.push_back(PriceSource {
-                source_type: String::from_str(&env, "admin"),
-                price: admin_price,
-                timestamp: env.ledger().timestamp(),
-                confidence: 70,
-            });
+        snapshots
+    }
+
+    /// Record a DEX price observation for the TWAP oracle (callable by any keeper, rate-limited per keeper)
+    pub fn record_price_observation(env: Env, keeper: Address, asset: Address) -> bool {
+        keeper.require_auth();
+        assert!(Self::is_asset_supported(&env, &asset), "Asset not supported");
+        
+        let key = (symbol_short!("twap_kpr"), keeper, asset.clone());
+        let current_time = env.ledger().timestamp();
+        if let Some(last) = env.storage().persistent().get::<_, u64>(&key) {
+            assert!(current_time >= last + TWAP_MIN_INTERVAL, "Observation rate limited");
         }
-        if let Some(mock_price) = Self::get_mock_price(&env, &asset) {
-            sources.push_back(PriceSource {
-                source_type: String::from_str(&env, "mock"),
-                price: mock_price,
-                timestamp: env.ledger().timestamp(),
-                confidence: 50,
-            });
+        env.storage().persistent().set(&key, &current_time);
+        
+        Self::record_twap_observation(&env, &asset)
+    }
+
+    /// Get the current DEX TWAP for an asset, if enough history exists
+    pub fn get_twap_price(env: Env, asset: Address) -> Option<u128> {
+        Self::get_dex_twap(&env, &asset).map(|(price, _)| price)
+    }
+
+    /// Get the recorded TWAP observations for an asset
+    pub fn get_twap_observations(env: Env, asset: Address) -> Vec<TwapObservation> {
+        env.storage()
+            .persistent()
+            .get::<_, TwapBuffer>(&(symbol_short!("twap"), asset))
+            .map(|buffer| buffer.observations)
+            .unwrap_or(Vec::new(&env))
+    }
+
+    /// Get the TWAP averaging window in seconds
+    pub fn get_twap_window(env: Env) -> u64 {
+        env.storage()
+            .instance()
+            .get(&symbol_short!("twap_win"))
+            .unwrap_or(TWAP_WINDOW)
+    }
+
+    /// Admin function to set the TWAP averaging window
+    pub fn set_twap_window(env: Env, admin: Address, window: u64) {
+        admin.require_auth();
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can set the TWAP window");
+        assert!(window >= TWAP_MIN_INTERVAL, "TWAP window too short");
+        assert!(window <= MAX_TWAP_WINDOW, "TWAP window exceeds observation history");
+        
+        env.storage().instance().set(&symbol_short!("twap_win"), &window);
+        
+        env.events().publish(
+            (symbol_short!("twap_win"), &admin),
+            window
+        );
+    }
+
+    /// Get the price aggregation settings for an asset (or the global default)
+    pub fn get_aggregation_config(env: Env, asset: Option<Address>) -> AggregationConfig {
+        if let Some(asset) = asset {
+            if let Some(config) = env.storage().persistent().get(&(symbol_short!("agg_cfg"), asset)) {
+                return config;
+            }
+        }
+        env.storage()
+            .instance()
+            .get(&symbol_short!("agg_cfg"))
+            .unwrap_or(AggregationConfig {
+                mode: AggregationMode::Median,
+                min_sources: 1,
+                max_deviation: MAX_PRICE_DEVIATION,
+            })
+    }
+
+    /// Admin function to set price aggregation settings globally or for one asset
+    pub fn set_aggregation_config(env: Env, admin: Address, asset: Option<Address>, config: AggregationConfig) {
+        admin.require_auth();
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can configure price aggregation");
+        assert!(config.min_sources > 0, "At least one source required");
+        assert!(config.max_deviation <= 10000, "Invalid max deviation");
+        
+        match &asset {
+            Some(asset) => env.storage().persistent().set(&(symbol_short!("agg_cfg"), asset.clone()), &config),
+            None => env.storage().instance().set(&symbol_short!("agg_cfg"), &config),
         }
-        sources
-    }
+        
+        env.events().publish(
+            (symbol_short!("agg_cfg"), &admin),
+            (asset, config.mode, config.min_sources, config.max_deviation)
+        );
+    }
+
+    /// Get the last accepted price and circuit breaker state for an asset
+    pub fn get_price_status(env: Env, asset: Address) -> PriceStatus {
+        env.storage()
+            .persistent()
+            .get(&(symbol_short!("prc_stat"), asset))
+            .unwrap_or(PriceStatus {
+                last_price: 0,
+                last_update: 0,
+                reference_price: 0,
+                window_start: 0,
+                tripped: false,
+                tripped_at: 0,
+            })
+    }
+
+    /// Get the price circuit breaker settings
+    pub fn get_circuit_breaker_config(env: Env) -> CircuitBreakerConfig {
+        env.storage()
+            .instance()
+            .get(&symbol_short!("cb_cfg"))
+            .unwrap_or(CircuitBreakerConfig {
+                max_change: CIRCUIT_BREAKER_MAX_CHANGE,
+                window: CIRCUIT_BREAKER_WINDOW,
+                cooldown: CIRCUIT_BREAKER_COOLDOWN,
+            })
+    }
+
+    /// Admin function to update the price circuit breaker settings
+    pub fn set_circuit_breaker_config(env: Env, admin: Address, config: CircuitBreakerConfig) {
+        admin.require_auth();
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can configure the circuit breaker");
+        assert!(config.max_change > 0 && config.window > 0, "Invalid circuit breaker config");
+        
+        env.storage().instance().set(&symbol_short!("cb_cfg"), &config);
+        
+        env.events().publish(
+            (symbol_short!("cb_cfg"), &admin),
+            (config.max_change, config.window, config.cooldown)
+        );
+    }
+
+    /// Admin function to clear a tripped circuit breaker, accepting `price` as the new reference
+    pub fn reset_circuit_breaker(env: Env, admin: Address, asset: Address, price: u128) {
+        admin.require_auth();
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can reset the circuit breaker");
+        assert!(price > 0, "Invalid price");
+        
+        let current_time = env.ledger().timestamp();
+        let status = PriceStatus {
+            last_price: price,
+            last_update: current_time,
+            reference_price: price,
+            window_start: current_time,
+            tripped: false,
+            tripped_at: 0,
+        };
+        env.storage().persistent().set(&(symbol_short!("prc_stat"), asset.clone()), &status);
+        
+        env.events().publish(
+            (symbol_short!("cb_reset"), asset),
+            price
+        );
+    }
+
+    /// Get the configured oracle sources for an asset, in priority order
+    pub fn get_oracle_sources(env: Env, asset: Address) -> Vec<OracleSource> {
+        env.storage()
+            .persistent()
+            .get(&(symbol_short!("orc_srcs"), asset))
+            .unwrap_or(Self::default_oracle_sources(&env))
+    }
+
+    /// Admin function to append a price source for an asset
+    pub fn add_price_source(env: Env, admin: Address, asset: Address, source: OracleSource) {
+        admin.require_auth();
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can configure price sources");
+        assert!(Self::is_asset_supported(&env, &asset), "Asset not supported");
+        
+        let mut sources = Self::get_oracle_sources(env.clone(), asset.clone());
+        sources.push_back(source.clone());
+        Self::save_oracle_sources(&env, &asset, &sources);
+        
+        env.events().publish(
+            (symbol_short!("src_add"), &admin),
+            (asset, source.source_type, source.address)
+        );
+    }
+
+    /// Admin function to remove the price source at `index` for an asset
+    pub fn remove_price_source(env: Env, admin: Address, asset: Address, index: u32) {
+        admin.require_auth();
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can configure price sources");
+        
+        let mut sources = Self::get_oracle_sources(env.clone(), asset.clone());
+        assert!(index < sources.len(), "Source index out of range");
+        let removed = sources.get(index).unwrap();
+        sources.remove(index);
+        Self::save_oracle_sources(&env, &asset, &sources);
+        
+        env.events().publish(
+            (symbol_short!("src_rm"), &admin),
+            (asset, removed.source_type, removed.address)
+        );
+    }
+
+    /// Admin function to move a price source to a new position for an asset
+    pub fn move_price_source(env: Env, admin: Address, asset: Address, from: u32, to: u32) {
+        admin.require_auth();
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can configure price sources");
+        
+        let mut sources = Self::get_oracle_sources(env.clone(), asset.clone());
+        assert!(from < sources.len() && to < sources.len(), "Source index out of range");
+        let source = sources.get(from).unwrap();
+        sources.remove(from);
+        sources.insert(to, source);
+        Self::save_oracle_sources(&env, &asset, &sources);
+        
+        env.events().publish(
+            (symbol_short!("src_move"), &admin),
+            (asset, from, to)
+        );
+    }
+
+    /// Admin function to replace the full price source list for an asset
+    pub fn set_price_sources(env: Env, admin: Address, asset: Address, sources: Vec<OracleSource>) {
+        admin.require_auth();
+        let stored_admin: Address = env.storage().instance().get(&symbol_short!("admin")).unwrap();
+        assert_eq!(admin, stored_admin, "Only admin can configure price sources");
+        assert!(Self::is_asset_supported(&env, &asset), "Asset not supported");
+        
+        Self::save_oracle_sources(&env, &asset, &sources);
+        
+        env.events().publish(
+            (symbol_short!("src_set"), &admin),
+            (asset, sources.len())
+        );
+    }
 }
\ No newline at end of file
@@ -97,7 +97,8 @@ export async function executeSwap(
         nativeToScVal(toToken, { type: 'address' }),
         nativeToScVal(amountInBigInt, { type: 'u128' }),
         nativeToScVal(minAmountOutBigInt, { type: 'u128' }),
-        nativeToScVal(BigInt(Math.floor(Date.now() / 1000) + 1200), { type: 'u64' })
+        nativeToScVal(BigInt(Math.floor(Date.now() / 1000) + 1200), { type: 'u64' }),
+        nativeToScVal(null) // referrer
       )
     )
     .setTimeout(30)
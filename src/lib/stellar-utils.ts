@@ -401,7 +401,8 @@ export async function swapTokens(
           nativeToScVal(tokenB, { type: 'address' }),
           nativeToScVal(amountIn, { type: 'u128' }),
           nativeToScVal(minAmountOut, { type: 'u128' }),
-          nativeToScVal(deadline, { type: 'u64' })
+          nativeToScVal(deadline, { type: 'u64' }),
+          nativeToScVal(null) // referrer
         )
       )
       .setTimeout(30)